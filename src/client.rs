@@ -1,44 +1,650 @@
-use super::proto::{MofosRequest, MofosResponse};
+use super::proto::{Hello, MofosRequest, MofosResponse, Status, MAX_READ, PROTOCOL_VERSION};
+use super::secure::{random_bytes, Key};
+use super::transport::{remote_command, Transport, TransportKind, MAX_MESSAGE};
 
-use std::io::Error;
-use std::net::{SocketAddr, UdpSocket};
-use std::process::Command;
+use std::collections::VecDeque;
+use std::convert::{TryFrom, TryInto};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Time without any request after which a keepalive is sent, well within the
+/// hour after which the server closes the handles of a silent client
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Transport to the server, shared with the thread sending keepalives
+struct Link {
+    transport: Box<dyn Transport>,
+    last_id: u64,
+    /// When the last request was sent
+    last_sent: Instant,
+}
+
+impl Link {
+    fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+
+        self.last_id
+    }
+
+    fn send_req(&mut self, req: MofosRequest) -> Result<MofosResponse, Error> {
+        if !req.expects_response() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "request does not expect a response",
+            ));
+        }
+
+        let id = req.id();
+        let bytes: Vec<u8> = req.try_into()?;
+
+        self.last_sent = Instant::now();
+
+        let payload = self.transport.call(id, bytes.as_slice())?;
+        let resp = MofosResponse::try_from(payload.as_slice())?;
+
+        if resp.id() != id {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("received response {} to request {}", resp.id(), id),
+            ));
+        }
+
+        Ok(resp)
+    }
+
+    fn notify(&mut self, req: MofosRequest) -> Result<(), Error> {
+        if req.expects_response() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "request expects a response",
+            ));
+        }
+
+        let id = req.id();
+        let bytes: Vec<u8> = req.try_into()?;
+
+        self.last_sent = Instant::now();
+
+        self.transport.send(id, bytes.as_slice())
+    }
+}
 
 pub struct Client {
-    socket: UdpSocket,
+    link: Arc<Mutex<Link>>,
+    /// Largest read the server can answer in a single response
+    max_read: usize,
 }
 
 impl Client {
-    pub fn new(dest: SocketAddr) -> Result<Client, Error> {
-        match UdpSocket::bind(dest) {
-            Ok(socket) => Ok(Client { socket }),
-            Err(e) => Err(e),
+    pub fn new(transport: Box<dyn Transport>) -> Client {
+        let link = Link {
+            transport,
+            last_id: 0,
+            last_sent: Instant::now(),
+        };
+
+        Client {
+            link: Arc::new(Mutex::new(link)),
+            max_read: MAX_READ,
         }
     }
 
+    fn link(&self) -> MutexGuard<'_, Link> {
+        self.link.lock().expect("keepalive thread panicked")
+    }
+
+    /// Opens the session offering `features`, returns what both ends of it
+    /// support. A server speaking another version of the protocol is refused.
+    pub fn hello(&mut self, features: u64) -> Result<Hello, Error> {
+        let mut session = [0u8; 8];
+
+        random_bytes(&mut session)?;
+
+        let ours = Hello {
+            version: PROTOCOL_VERSION,
+            features,
+            max_message: MAX_MESSAGE as u32,
+            session: u64::from_ne_bytes(session),
+        };
+        let req = MofosRequest::Hello {
+            id: self.next_id(),
+            hello: ours.clone(),
+        };
+
+        let theirs = match self.send_req(req)? {
+            MofosResponse::Hello(_, Status::Ok, theirs) if theirs.version == ours.version => theirs,
+
+            MofosResponse::Hello(_, _, theirs) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "server speaks protocol version {}, we speak {}",
+                        theirs.version, ours.version
+                    ),
+                ))
+            }
+
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "server does not speak the protocol",
+                ))
+            }
+        };
+        let session = ours.negotiate(&theirs);
+
+        self.max_read = session.max_read();
+
+        Ok(session)
+    }
+
+    /// Largest read to ask the server for at once
+    pub fn max_read(&self) -> usize {
+        self.max_read
+    }
+
+    /// Identifier for the next request, unique for the lifetime of the client
+    pub fn next_id(&mut self) -> u64 {
+        self.link().next_id()
+    }
+
     pub fn send_req(&mut self, req: MofosRequest) -> Result<MofosResponse, Error> {
-        panic!("send_req unimplemented");
+        self.link().send_req(req)
     }
+
+    /// Sends a request that gets no response
+    pub fn notify(&mut self, req: MofosRequest) -> Result<(), Error> {
+        self.link().notify(req)
+    }
+
+    /// Sends a keepalive whenever no request was sent for `interval`, for the
+    /// server not to take an idle client for gone. This stops along with the
+    /// client.
+    pub fn keepalive(&self, interval: Duration) {
+        let link = Arc::downgrade(&self.link);
+
+        thread::spawn(move || {
+            let mut wait = interval;
+
+            loop {
+                thread::sleep(wait);
+
+                let link = match link.upgrade() {
+                    Some(link) => link,
+                    None => return,
+                };
+                let mut link = match link.lock() {
+                    Ok(link) => link,
+                    Err(_) => return,
+                };
+                let idle = link.last_sent.elapsed();
+
+                if idle < interval {
+                    wait = interval - idle;
+                    continue;
+                }
+
+                wait = interval;
+
+                let req = MofosRequest::KeepAlive { id: link.next_id() };
+
+                match link.send_req(req) {
+                    Ok(MofosResponse::KeepAlive(_, Status::Ok)) => debug!("keepalive answered"),
+                    Ok(_) => warn!("keepalive refused"),
+                    Err(e) => warn!("keepalive failed: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// How the server is started on the remote host
+#[derive(Clone, Debug)]
+pub struct RemoteConfig {
+    /// Command used to reach the remote host along with its options, the host
+    /// is appended to it
+    pub ssh: Vec<String>,
+    pub host: String,
+    /// Path of the server binary on the remote host
+    pub binary: String,
+}
+
+impl RemoteConfig {
+    /// Program and arguments the remote command line is given to
+    pub fn launcher(&self) -> Vec<String> {
+        let mut launcher = self.ssh.clone();
+
+        launcher.push(self.host.clone());
+
+        launcher
+    }
+
+    /// Command line starting the server for `transport` on `port`, exporting
+    /// `dir` and sending datagrams of at most `mtu` bytes
+    pub fn server_args(
+        &self,
+        transport: TransportKind,
+        port: u16,
+        mtu: usize,
+        dir: &str,
+    ) -> Vec<String> {
+        let mut args = vec![
+            self.binary.clone(),
+            String::from("-T"),
+            transport.to_string(),
+            String::from("-t"),
+            String::from(dir),
+        ];
+
+        // in stdio mode the requests come through stdin, otherwise its end
+        // tells the server that the client is gone
+        if transport != TransportKind::Stdio {
+            args.push(String::from("-p"));
+            args.push(port.to_string());
+            args.push(String::from("--watch-stdin"));
+        }
+
+        if transport == TransportKind::Udp {
+            args.push(String::from("-m"));
+            args.push(mtu.to_string());
+        }
+
+        args
+    }
+}
+
+/// Number of lines of the server's stderr kept for error reporting
+const STDERR_LINES: usize = 32;
+
+/// Time the server is given to fail before it is connected to, later
+/// failures are caught by `RemoteServer::check`
+const STARTUP_GRACE: Duration = Duration::from_millis(500);
+
+/// Server running in the background on the remote host, stopped when dropped
+pub struct RemoteServer {
+    child: Child,
+    /// Kept open for as long as the server should run, the server exits once
+    /// it is closed
+    _stdin: ChildStdin,
+    stderr: Option<JoinHandle<Vec<String>>>,
 }
 
+impl RemoteServer {
+    /// Fails with what the server printed on stderr if it exited
+    pub fn check(&mut self) -> Result<(), Error> {
+        match self.child.try_wait()? {
+            Some(status) => Err(Error::other(format!(
+                "remote server exited with {}: {}",
+                status,
+                self.stderr()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Collects what the server printed on stderr once it exited
+    fn stderr(&mut self) -> String {
+        match self.stderr.take().map(|t| t.join()) {
+            Some(Ok(lines)) => lines.join("\n"),
+            _ => String::new(),
+        }
+    }
+}
+
+impl Drop for RemoteServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.child.kill() {
+            debug!("remote server already exited: {}", e);
+        }
+
+        if let Err(e) = self.child.wait() {
+            warn!("failed to reap remote server: {}", e);
+        }
+    }
+}
+
+/// Starts the server listening on `port` for `transport` and exporting `dir`
+/// on the remote host described by `remote`, its datagrams are no larger than
+/// `mtu`. When `key` is given it is handed to the server through the ssh
+/// channel and the server only accepts messages sealed with it.
 pub fn spawn_remote_server(
-    addr: &SocketAddr,
-    server: String,
+    remote: &RemoteConfig,
+    transport: TransportKind,
     port: u16,
-    dir: String,
-) -> Result<(), Error> {
-    debug!("spawning remote server using ssh");
-
-    // FIXME: parametric server spawning
-    let result = Command::new("ssh")
-        .arg(server)
-        .arg(format!("-p {}", port))
-        .arg("mofos-server -p 6000 -t /tmp")
-        .output()?;
-
-    if result.status.success() {
-        Ok(())
-    } else {
-        Err(Error {})
+    mtu: usize,
+    dir: &str,
+    key: Option<&Key>,
+) -> Result<RemoteServer, Error> {
+    debug!("spawning remote server on {} using ssh", remote.host);
+
+    let mut args = remote.server_args(transport, port, mtu, dir);
+
+    if key.is_some() {
+        args.push(String::from("--key-stdin"));
+    }
+
+    let mut child = remote_command(&remote.launcher(), &args)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // the key never shows up on a command line or in the environment of the
+    // remote host
+    let mut stdin = child.stdin.take().expect("missing server stdin");
+
+    if let Some(key) = key {
+        if let Err(e) = writeln!(stdin, "{}", key.to_hex()) {
+            debug!("failed to send key to server: {}", e);
+        }
+    }
+
+    let stderr = child.stderr.take().expect("missing server stderr");
+
+    // the server's stderr must be drained for it not to block on logging
+    let reader = thread::spawn(move || {
+        let mut lines = VecDeque::with_capacity(STDERR_LINES);
+
+        for line in BufReader::new(stderr).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            info!("server: {}", line);
+
+            if lines.len() == STDERR_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+
+        lines.into_iter().collect()
+    });
+
+    let mut server = RemoteServer {
+        child,
+        _stdin: stdin,
+        stderr: Some(reader),
+    };
+    let started = Instant::now();
+
+    while started.elapsed() < STARTUP_GRACE {
+        server.check()?;
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(server)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate libc;
+
+    use std::convert::TryFrom;
+    use std::io::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::super::proto::{
+        Hello, MofosRequest, MofosResponse, Status, FEATURE_XATTR, MAX_READ, PROTOCOL_VERSION,
+    };
+    use super::super::secure::Key;
+    use super::super::transport::{Transport, TransportKind, DEFAULT_MTU};
+    use super::{spawn_remote_server, Client, RemoteConfig};
+
+    /// Answers every request with the same response
+    struct Canned(Vec<u8>);
+
+    impl Transport for Canned {
+        fn call(&mut self, _id: u64, _req: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(self.0.clone())
+        }
+
+        fn send(&mut self, _id: u64, _req: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Answers keepalives, counting them
+    struct KeepAlives(Arc<AtomicUsize>);
+
+    impl Transport for KeepAlives {
+        fn call(&mut self, _id: u64, req: &[u8]) -> Result<Vec<u8>, Error> {
+            match MofosRequest::try_from(req)? {
+                MofosRequest::KeepAlive { id } => {
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                    Ok(MofosResponse::KeepAlive(id, Status::Ok).into())
+                }
+                _ => panic!("unexpected request"),
+            }
+        }
+
+        fn send(&mut self, _id: u64, _req: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn client_answered(status: Status, hello: Hello) -> Client {
+        Client::new(Box::new(Canned(
+            MofosResponse::Hello(1, status, hello).into(),
+        )))
+    }
+
+    fn local(binary: &str) -> RemoteConfig {
+        // run the server through a local shell instead of ssh
+        RemoteConfig {
+            ssh: vec![String::from("sh")],
+            host: String::from("-c"),
+            binary: String::from(binary),
+        }
+    }
+
+    #[test]
+    fn server_args_test() {
+        let remote = local("mofos-server");
+
+        assert_eq!(
+            remote.server_args(TransportKind::Udp, 6000, 1400, "/a dir"),
+            vec![
+                "mofos-server",
+                "-T",
+                "udp",
+                "-t",
+                "/a dir",
+                "-p",
+                "6000",
+                "--watch-stdin",
+                "-m",
+                "1400"
+            ]
+        );
+        assert_eq!(
+            remote.server_args(TransportKind::Tcp, 6000, 1400, "/tmp"),
+            vec![
+                "mofos-server",
+                "-T",
+                "tcp",
+                "-t",
+                "/tmp",
+                "-p",
+                "6000",
+                "--watch-stdin"
+            ]
+        );
+        assert_eq!(
+            remote.server_args(TransportKind::Stdio, 6000, 1400, "/tmp"),
+            vec!["mofos-server", "-T", "stdio", "-t", "/tmp"]
+        );
+    }
+
+    #[test]
+    fn spawn_reports_stderr_test() {
+        let remote = local("echo 'no such directory' >&2; exit 1;");
+
+        match spawn_remote_server(&remote, TransportKind::Udp, 6000, DEFAULT_MTU, "/tmp", None) {
+            Ok(_) => panic!("server should have failed"),
+            Err(e) => assert!(e.to_string().contains("no such directory")),
+        }
+    }
+
+    #[test]
+    fn spawn_keeps_server_running_test() {
+        let remote = local("exec sleep 5;");
+
+        assert!(
+            spawn_remote_server(&remote, TransportKind::Udp, 6000, DEFAULT_MTU, "/tmp", None)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn check_reports_late_failure_test() {
+        let remote = local("sleep 1; echo 'address in use' >&2; exit 1;");
+        let mut server =
+            spawn_remote_server(&remote, TransportKind::Udp, 6000, DEFAULT_MTU, "/tmp", None)
+                .expect("server failed early");
+        let started = Instant::now();
+
+        loop {
+            match server.check() {
+                Ok(()) if started.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Ok(()) => panic!("server failure not noticed"),
+                Err(e) => {
+                    assert!(e.to_string().contains("address in use"));
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_keeps_stdin_open_test() {
+        // the server fails as soon as its stdin is closed
+        let remote = local("read line; exit 1;");
+
+        assert!(
+            spawn_remote_server(&remote, TransportKind::Udp, 6000, DEFAULT_MTU, "/tmp", None)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn spawn_sends_key_test() {
+        // the server echoes back what it read on stdin before failing
+        let remote = local("read key; echo \"key $key\" >&2; exit 1;");
+        let key = Key::generate().expect("no randomness");
+
+        match spawn_remote_server(
+            &remote,
+            TransportKind::Udp,
+            6000,
+            DEFAULT_MTU,
+            "/tmp",
+            Some(&key),
+        ) {
+            Ok(_) => panic!("server should have failed"),
+            Err(e) => assert!(e.to_string().contains(&format!("key {}", key.to_hex()))),
+        }
+    }
+
+    #[test]
+    fn send_req_matches_response_id_test() {
+        let mut client = client_answered(Status::Ok, Hello::default());
+        let req = MofosRequest::GetAttr {
+            id: 1,
+            path: String::new(),
+        };
+
+        assert!(matches!(
+            client.send_req(req),
+            Ok(MofosResponse::Hello(1, Status::Ok, _))
+        ));
+
+        // the response carries the id of another request
+        let req = MofosRequest::GetAttr {
+            id: 2,
+            path: String::new(),
+        };
+
+        match client.send_req(req) {
+            Ok(_) => panic!("response to another request accepted"),
+            Err(e) => assert!(e.to_string().contains("response 1 to request 2")),
+        }
+    }
+
+    #[test]
+    fn send_req_refuses_exit_test() {
+        let mut client = client_answered(Status::Ok, Hello::default());
+
+        assert!(client.send_req(MofosRequest::Exit { id: 1 }).is_err());
+    }
+
+    #[test]
+    fn keepalive_test() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let client = Client::new(Box::new(KeepAlives(sent.clone())));
+
+        client.keepalive(Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(sent.load(Ordering::SeqCst) > 0);
+
+        // the thread stops along with the client
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+
+        let stopped = sent.load(Ordering::SeqCst);
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sent.load(Ordering::SeqCst), stopped);
+    }
+
+    #[test]
+    fn notify_refuses_request_test() {
+        let mut client = client_answered(Status::Ok, Hello::default());
+
+        assert!(client.notify(MofosRequest::Exit { id: 1 }).is_ok());
+        assert!(client.notify(MofosRequest::StatFs { id: 2 }).is_err());
+    }
+
+    #[test]
+    fn hello_negotiates_test() {
+        let theirs = Hello {
+            version: PROTOCOL_VERSION,
+            features: 0,
+            max_message: 64 << 10,
+            session: 0,
+        };
+        let mut client = client_answered(Status::Ok, theirs);
+
+        assert_eq!(client.max_read(), MAX_READ);
+
+        let session = client.hello(FEATURE_XATTR).expect("hello failed");
+
+        assert!(!session.has(FEATURE_XATTR));
+        assert!(client.max_read() < 64 << 10);
+    }
+
+    #[test]
+    fn hello_refuses_other_version_test() {
+        let theirs = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Default::default()
+        };
+        let mut client = client_answered(Status::Err(libc::EPROTONOSUPPORT), theirs);
+
+        match client.hello(0) {
+            Ok(_) => panic!("session opened with another version"),
+            Err(e) => assert!(e.to_string().contains("protocol version")),
+        }
     }
 }
@@ -0,0 +1,408 @@
+extern crate fuse;
+
+use std::collections::HashMap;
+
+use self::fuse::FUSE_ROOT_ID;
+
+struct Inode {
+    /// Names of the file as its parent's inode and its name in there, the
+    /// first one makes up its path. Hard links add more, while a file left
+    /// with none was unlinked: the kernel may still refer to it but it no
+    /// longer has a path.
+    links: Vec<(u64, String)>,
+    /// Identity of the file on the server, `None` for directories
+    file: Option<(u64, u64)>,
+    /// Number of lookups the kernel did not forget yet
+    lookups: u64,
+    /// Number of links in this directory, a directory is kept as long as any
+    /// of its entries is known
+    children: u64,
+}
+
+impl Inode {
+    fn new(link: (u64, String), file: Option<(u64, u64)>) -> Inode {
+        Inode {
+            links: vec![link],
+            file,
+            lookups: 1,
+            children: 0,
+        }
+    }
+}
+
+/// Maps the remote files the kernel knows of to local inode numbers.
+///
+/// Only the name of each file and the inode of its parent are kept so that
+/// renaming a directory does not need to touch its entries. Hard links to a
+/// file share its inode, as they do on the server. Inodes are freed once the
+/// kernel forgot every lookup of them and of their entries, which keeps
+/// memory bounded by what the kernel caches. Inode numbers are never reused.
+pub struct InodeTable {
+    inodes: HashMap<u64, Inode>,
+    names: HashMap<(u64, String), u64>,
+    /// Inodes of the files with a path, by their identity on the server
+    files: HashMap<(u64, u64), u64>,
+    last_ino: u64,
+}
+
+impl InodeTable {
+    /// Creates a table only knowing the root, the exported directory
+    pub fn new() -> InodeTable {
+        let mut inodes = HashMap::new();
+        let root = Inode {
+            links: Vec::new(),
+            file: None,
+            lookups: 1,
+            children: 0,
+        };
+
+        inodes.insert(FUSE_ROOT_ID, root);
+
+        InodeTable {
+            inodes,
+            names: HashMap::new(),
+            files: HashMap::new(),
+            last_ino: FUSE_ROOT_ID,
+        }
+    }
+
+    /// Remote path of `ino`, relative to the exported directory, `None` if
+    /// the inode is unknown or was detached from the tree
+    pub fn path(&self, ino: u64) -> Option<String> {
+        let mut names = Vec::new();
+        let mut current = ino;
+
+        while current != FUSE_ROOT_ID {
+            let (parent, name) = self.inodes.get(&current)?.links.first()?;
+
+            names.push(name.as_str());
+            current = *parent;
+        }
+
+        names.reverse();
+
+        Some(names.join("/"))
+    }
+
+    /// Inode of the directory containing `ino`, the root is its own parent
+    pub fn parent(&self, ino: u64) -> Option<u64> {
+        if ino == FUSE_ROOT_ID {
+            return Some(FUSE_ROOT_ID);
+        }
+
+        self.inodes.get(&ino)?.links.first().map(|link| link.0)
+    }
+
+    /// Records a successful lookup of `name` in `parent`, `file` being the
+    /// identity of the entry on the server as given by
+    /// `FileAttr::file_id`. Returns the inode number of the entry, which is
+    /// allocated on the first lookup of any of its links.
+    pub fn lookup(&mut self, parent: u64, name: &str, file: Option<(u64, u64)>) -> u64 {
+        let key = (parent, String::from(name));
+
+        if let Some(&ino) = self.names.get(&key) {
+            if let Some(inode) = self.inodes.get_mut(&ino) {
+                inode.lookups += 1;
+            }
+
+            return ino;
+        }
+
+        let ino = match file.and_then(|file| self.files.get(&file).copied()) {
+            // another name of a file already known, it was just found so it
+            // makes a path that certainly still exists
+            Some(ino) => {
+                if let Some(inode) = self.inodes.get_mut(&ino) {
+                    inode.links.insert(0, key.clone());
+                    inode.lookups += 1;
+                }
+
+                ino
+            }
+
+            None => {
+                self.last_ino += 1;
+                self.inodes
+                    .insert(self.last_ino, Inode::new(key.clone(), file));
+
+                if let Some(file) = file {
+                    self.files.insert(file, self.last_ino);
+                }
+
+                self.last_ino
+            }
+        };
+
+        self.names.insert(key, ino);
+
+        // the entry now keeps its directory around
+        if let Some(inode) = self.inodes.get_mut(&parent) {
+            inode.children += 1;
+        }
+
+        ino
+    }
+
+    /// Inode of `name` in `parent` if the kernel looked it up, nothing is
+    /// allocated for entries it only saw in a listing
+    pub fn find(&self, parent: u64, name: &str) -> Option<u64> {
+        self.names.get(&(parent, String::from(name))).copied()
+    }
+
+    /// Drops `nlookup` lookups of `ino`, freeing it once none is left
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups = inode.lookups.saturating_sub(nlookup);
+        }
+
+        self.collect(ino);
+    }
+
+    /// Updates the mappings after `name` in `parent` was renamed to `newname`
+    /// in `newparent`, the entries of a directory follow it
+    pub fn rename(&mut self, parent: u64, name: &str, newparent: u64, newname: &str) {
+        let source = (parent, String::from(name));
+        let target = (newparent, String::from(newname));
+        let ino = self.names.get(&source).copied();
+
+        // renaming a file onto one of its own links does nothing
+        if source == target || (ino.is_some() && ino == self.names.get(&target).copied()) {
+            return;
+        }
+
+        // the rename replaced whatever was at the destination
+        self.unlink(newparent, newname);
+
+        let ino = match ino {
+            Some(ino) => ino,
+            None => return,
+        };
+
+        self.names.remove(&source);
+
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            for link in inode.links.iter_mut().filter(|link| **link == source) {
+                *link = target.clone();
+            }
+        }
+
+        if let Some(inode) = self.inodes.get_mut(&parent) {
+            inode.children -= 1;
+        }
+
+        if let Some(inode) = self.inodes.get_mut(&newparent) {
+            inode.children += 1;
+        }
+
+        self.names.insert(target, ino);
+        self.collect(parent);
+    }
+
+    /// Drops `name` from `parent` after it was removed, the file is detached
+    /// from the tree once it has no link left
+    pub fn unlink(&mut self, parent: u64, name: &str) {
+        let key = (parent, String::from(name));
+        let ino = match self.names.remove(&key) {
+            Some(ino) => ino,
+            None => return,
+        };
+
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.links.retain(|link| *link != key);
+
+            // the server may hand its identity to another file once this one
+            // is gone
+            if let (true, Some(file)) = (inode.links.is_empty(), inode.file) {
+                self.files.remove(&file);
+            }
+        }
+
+        // the kernel still holds a lookup of the directory, it is freed on
+        // forget
+        if let Some(inode) = self.inodes.get_mut(&parent) {
+            inode.children -= 1;
+        }
+    }
+
+    /// Frees `ino` and then the directories it is in for as long as nothing
+    /// refers to them
+    fn collect(&mut self, ino: u64) {
+        let mut pending = vec![ino];
+
+        while let Some(ino) = pending.pop() {
+            let unused = self
+                .inodes
+                .get(&ino)
+                .is_some_and(|inode| inode.lookups == 0 && inode.children == 0);
+
+            if ino == FUSE_ROOT_ID || !unused {
+                continue;
+            }
+
+            let inode = match self.inodes.remove(&ino) {
+                Some(inode) => inode,
+                None => continue,
+            };
+
+            if let Some(file) = inode.file {
+                if self.files.get(&file) == Some(&ino) {
+                    self.files.remove(&file);
+                }
+            }
+
+            for link in inode.links {
+                if let Some(parent) = self.inodes.get_mut(&link.0) {
+                    parent.children -= 1;
+                }
+
+                pending.push(link.0);
+                self.names.remove(&link);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InodeTable, FUSE_ROOT_ID};
+
+    #[test]
+    fn lookup_is_stable_test() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(FUSE_ROOT_ID, "dir", None);
+        let file = table.lookup(dir, "file", None);
+
+        assert_eq!(table.lookup(FUSE_ROOT_ID, "dir", None), dir);
+        assert_ne!(file, dir);
+        assert_eq!(table.path(FUSE_ROOT_ID).unwrap(), "");
+        assert_eq!(table.path(dir).unwrap(), "dir");
+        assert_eq!(table.path(file).unwrap(), "dir/file");
+        assert_eq!(table.parent(file), Some(dir));
+        assert_eq!(table.parent(FUSE_ROOT_ID), Some(FUSE_ROOT_ID));
+    }
+
+    #[test]
+    fn forget_frees_inodes_test() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(FUSE_ROOT_ID, "dir", None);
+        let file = table.lookup(dir, "file", None);
+
+        table.lookup(dir, "file", None);
+
+        // the directory is kept as long as one of its entries is known
+        table.forget(dir, 1);
+        assert!(table.path(dir).is_some());
+
+        table.forget(file, 1);
+        assert!(table.path(file).is_some());
+
+        table.forget(file, 1);
+        assert!(table.path(file).is_none());
+        assert!(table.path(dir).is_none());
+        assert_eq!(table.inodes.len(), 1);
+
+        // forgotten files get a new inode when looked up again
+        assert_ne!(table.lookup(FUSE_ROOT_ID, "dir", None), dir);
+    }
+
+    #[test]
+    fn rename_moves_entries_test() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(FUSE_ROOT_ID, "dir", None);
+        let file = table.lookup(dir, "file", None);
+        let other = table.lookup(FUSE_ROOT_ID, "other", None);
+
+        table.rename(FUSE_ROOT_ID, "dir", other, "moved");
+
+        assert_eq!(table.path(dir).unwrap(), "other/moved");
+        assert_eq!(table.path(file).unwrap(), "other/moved/file");
+        assert_eq!(table.lookup(other, "moved", None), dir);
+    }
+
+    #[test]
+    fn rename_replaces_destination_test() {
+        let mut table = InodeTable::new();
+        let source = table.lookup(FUSE_ROOT_ID, "source", None);
+        let target = table.lookup(FUSE_ROOT_ID, "target", None);
+
+        table.rename(FUSE_ROOT_ID, "source", FUSE_ROOT_ID, "target");
+
+        assert_eq!(table.path(source).unwrap(), "target");
+        assert!(table.path(target).is_none());
+
+        table.forget(target, 1);
+        table.forget(source, 1);
+        assert_eq!(table.inodes.len(), 1);
+    }
+
+    #[test]
+    fn find_does_not_allocate_test() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(FUSE_ROOT_ID, "dir", None);
+
+        assert_eq!(table.find(dir, "file"), None);
+        assert_eq!(table.inodes.len(), 2);
+
+        let file = table.lookup(dir, "file", None);
+
+        assert_eq!(table.find(dir, "file"), Some(file));
+        assert_eq!(table.find(FUSE_ROOT_ID, "dir"), Some(dir));
+    }
+
+    #[test]
+    fn links_share_inode_test() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(FUSE_ROOT_ID, "dir", None);
+        let file = table.lookup(FUSE_ROOT_ID, "file", Some((1, 42)));
+        let link = table.lookup(dir, "link", Some((1, 42)));
+
+        assert_eq!(link, file);
+        assert_eq!(table.path(file).unwrap(), "dir/link");
+
+        // the file keeps a path as long as one of its links is left
+        table.unlink(dir, "link");
+        assert_eq!(table.path(file).unwrap(), "file");
+        assert_eq!(table.find(dir, "link"), None);
+
+        table.unlink(FUSE_ROOT_ID, "file");
+        assert!(table.path(file).is_none());
+
+        // a new file may get the identity of one that was removed
+        assert_ne!(table.lookup(dir, "new", Some((1, 42))), file);
+
+        table.forget(file, 2);
+        table.forget(dir, 1);
+        assert!(table.path(dir).is_some());
+        assert_eq!(table.inodes.len(), 3);
+    }
+
+    #[test]
+    fn links_are_freed_with_their_file_test() {
+        let mut table = InodeTable::new();
+        let dir = table.lookup(FUSE_ROOT_ID, "dir", None);
+        let file = table.lookup(FUSE_ROOT_ID, "file", Some((1, 42)));
+
+        table.lookup(dir, "link", Some((1, 42)));
+
+        // the link keeps the directory around until the file is forgotten
+        table.forget(dir, 1);
+        assert!(table.path(dir).is_some());
+
+        table.forget(file, 2);
+        assert_eq!(table.inodes.len(), 1);
+        assert!(table.names.is_empty());
+        assert!(table.files.is_empty());
+    }
+
+    #[test]
+    fn rename_onto_link_test() {
+        let mut table = InodeTable::new();
+        let file = table.lookup(FUSE_ROOT_ID, "file", Some((1, 42)));
+
+        table.lookup(FUSE_ROOT_ID, "link", Some((1, 42)));
+        table.rename(FUSE_ROOT_ID, "file", FUSE_ROOT_ID, "link");
+
+        assert_eq!(table.find(FUSE_ROOT_ID, "file"), Some(file));
+        assert_eq!(table.find(FUSE_ROOT_ID, "link"), Some(file));
+    }
+}
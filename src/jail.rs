@@ -0,0 +1,244 @@
+extern crate libc;
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+
+/// Number of symbolic links followed while resolving a single path
+const MAX_SYMLINKS: usize = 40;
+
+fn escape() -> Error {
+    Error::new(
+        ErrorKind::PermissionDenied,
+        "path escapes the exported directory",
+    )
+}
+
+/// Confines the paths received from clients to the exported directory.
+///
+/// Paths are always relative to the exported directory, absolute paths and
+/// `..` components are rejected and symbolic links are resolved here so that
+/// they cannot point outside of it. This does not protect against another
+/// process swapping directories for symlinks behind our back, the server
+/// should be chrooted when that matters.
+pub struct Jail {
+    root: PathBuf,
+}
+
+impl Jail {
+    pub fn new(root: &Path) -> Result<Jail, Error> {
+        let root = root.canonicalize()?;
+
+        if !root.is_dir() {
+            return Err(Error::other("is not a directory"));
+        }
+
+        Ok(Jail { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves the client supplied `path`, following every symbolic link
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, Error> {
+        self.walk(path, true)
+    }
+
+    /// Resolves the client supplied `path` without following a symbolic link
+    /// in its last component, for operations acting on the link itself
+    pub fn resolve_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.walk(path, false)
+    }
+
+    fn walk(&self, path: &str, follow: bool) -> Result<PathBuf, Error> {
+        // components left to resolve, in reverse order
+        let mut pending = Vec::new();
+        let mut resolved: Vec<OsString> = Vec::new();
+        let mut links = 0;
+
+        for component in Path::new(path).components().rev() {
+            match component {
+                Component::Normal(name) => pending.push(name.to_os_string()),
+                Component::CurDir => continue,
+                _ => return Err(escape()),
+            }
+        }
+
+        while let Some(name) = pending.pop() {
+            // only symbolic link targets may go up, as long as they stay inside
+            if name == ".." {
+                resolved.pop().ok_or_else(escape)?;
+                continue;
+            }
+
+            let candidate = self.join(&resolved).join(&name);
+
+            match fs::symlink_metadata(&candidate) {
+                Ok(ref md) if md.file_type().is_symlink() && (follow || !pending.is_empty()) => {
+                    links += 1;
+
+                    if links > MAX_SYMLINKS {
+                        return Err(Error::from_raw_os_error(libc::ELOOP));
+                    }
+
+                    for component in fs::read_link(&candidate)?.components().rev() {
+                        match component {
+                            Component::Normal(name) => pending.push(name.to_os_string()),
+                            Component::ParentDir => pending.push(OsString::from("..")),
+                            Component::CurDir => continue,
+                            _ => return Err(escape()),
+                        }
+                    }
+                }
+
+                // missing files are left to the operation to report, they may
+                // also be about to be created
+                _ => resolved.push(name),
+            }
+        }
+
+        Ok(self.join(&resolved))
+    }
+
+    fn join(&self, components: &[OsString]) -> PathBuf {
+        components
+            .iter()
+            .fold(self.root.clone(), |path, name| path.join(name))
+    }
+
+    /// Makes the exported directory the root of the filesystem for this
+    /// process, requires root privileges
+    pub fn chroot(&mut self) -> Result<(), Error> {
+        let root = CString::new(self.root.as_os_str().as_bytes())?;
+
+        if unsafe { libc::chroot(root.as_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        env::set_current_dir("/")?;
+        self.root = PathBuf::from("/");
+
+        Ok(())
+    }
+}
+
+/// Permanently switches to `uid` and `gid`, dropping supplementary groups
+pub fn drop_privileges(uid: u32, gid: u32) -> Result<(), Error> {
+    unsafe {
+        if libc::setgroups(0, ptr::null()) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0
+        {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate mktemp;
+
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use self::mktemp::Temp;
+    use super::Jail;
+
+    fn setup_test() -> (Jail, Temp) {
+        let temp = Temp::new_dir().expect("could not create temp dir");
+        let root = temp.to_path_buf().join("root");
+
+        fs::create_dir_all(root.join("dir")).expect("failed to create root");
+        fs::write(root.join("dir/file"), b"content").expect("failed to create file");
+        fs::write(temp.to_path_buf().join("secret"), b"secret").expect("failed to create file");
+
+        (Jail::new(&root).expect("invalid root"), temp)
+    }
+
+    #[test]
+    fn resolves_inside_test() {
+        let (jail, _tmp) = setup_test();
+        let root = jail.root().to_path_buf();
+
+        assert_eq!(jail.resolve("").unwrap(), root);
+        assert_eq!(jail.resolve(".").unwrap(), root);
+        assert_eq!(jail.resolve("dir/./file").unwrap(), root.join("dir/file"));
+        assert_eq!(jail.resolve("dir/new").unwrap(), root.join("dir/new"));
+    }
+
+    #[test]
+    fn rejects_parent_test() {
+        let (jail, _tmp) = setup_test();
+
+        assert!(jail.resolve("..").is_err());
+        assert!(jail.resolve("../secret").is_err());
+        // even when it would stay inside
+        assert!(jail.resolve("dir/../dir/file").is_err());
+    }
+
+    #[test]
+    fn rejects_absolute_test() {
+        let (jail, _tmp) = setup_test();
+
+        assert!(jail.resolve("/etc/shadow").is_err());
+        assert!(jail.resolve("/").is_err());
+    }
+
+    #[test]
+    fn rejects_absolute_symlink_test() {
+        let (jail, tmp) = setup_test();
+
+        symlink(tmp.to_path_buf().join("secret"), jail.root().join("link")).unwrap();
+
+        assert!(jail.resolve("link").is_err());
+    }
+
+    #[test]
+    fn rejects_relative_symlink_escape_test() {
+        let (jail, _tmp) = setup_test();
+
+        symlink("../../secret", jail.root().join("dir/link")).unwrap();
+
+        assert!(jail.resolve("dir/link").is_err());
+    }
+
+    #[test]
+    fn rejects_symlinked_directory_escape_test() {
+        let (jail, _tmp) = setup_test();
+
+        symlink("..", jail.root().join("up")).unwrap();
+
+        assert!(jail.resolve("up/secret").is_err());
+        assert!(jail.resolve_link("up/secret").is_err());
+    }
+
+    #[test]
+    fn follows_symlink_inside_test() {
+        let (jail, _tmp) = setup_test();
+        let root = jail.root().to_path_buf();
+
+        symlink("../dir/file", root.join("dir/link")).unwrap();
+        symlink("dir", root.join("alias")).unwrap();
+
+        assert_eq!(jail.resolve("dir/link").unwrap(), root.join("dir/file"));
+        assert_eq!(jail.resolve("alias/file").unwrap(), root.join("dir/file"));
+        assert_eq!(
+            jail.resolve_link("dir/link").unwrap(),
+            root.join("dir/link")
+        );
+    }
+
+    #[test]
+    fn rejects_symlink_loop_test() {
+        let (jail, _tmp) = setup_test();
+
+        symlink("loop", jail.root().join("loop")).unwrap();
+
+        assert!(jail.resolve("loop").is_err());
+    }
+}
@@ -5,10 +5,18 @@ extern crate serde_derive;
 extern crate log;
 
 mod proto;
+mod secure;
+mod transport;
+
+#[cfg(not(feature = "client"))]
+mod jail;
 
 #[cfg(not(feature = "client"))]
 mod server;
 
+#[cfg(feature = "client")]
+mod inode;
+
 #[cfg(feature = "client")]
 mod mofos;
 
@@ -27,24 +35,58 @@ fn common_init() {
 mod main {
     use std::env;
     use std::ffi::OsStr;
-    use std::net::SocketAddr;
+    use std::io::{Error, ErrorKind};
+    use std::net::ToSocketAddrs;
+    use std::path::PathBuf;
     use std::process;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
 
     use log::{error, info};
 
-    use self::client::Client;
+    use self::client::{spawn_remote_server, Client, RemoteConfig, RemoteServer};
     use self::mofos::MofosFS;
 
     use super::client;
     use super::common_init;
     use super::mofos;
+    use super::proto::{Hello, FEATURE_XATTR};
+    use super::secure::Key;
+    use super::transport::{
+        PipeTransport, RetryPolicy, TcpTransport, Transport, TransportKind, UdpTransport,
+        DEFAULT_MTU, MAX_MTU, MIN_MTU,
+    };
+
+    /// Port the server listens on unless told otherwise
+    const DEFAULT_PORT: u16 = 6000;
+
+    /// Server binary started on the remote host unless told otherwise
+    const DEFAULT_BINARY: &str = "mofos-server";
+
+    /// Time a server we started is given to start listening
+    const SERVER_STARTUP: Duration = Duration::from_secs(30);
 
+    /// Interval at which a server we started is checked while connecting
+    const STARTUP_POLL: Duration = Duration::from_millis(50);
+
+    #[derive(Clone)]
     struct MofosConfig {
         fuse_args: Vec<String>,
-        host: String,
+        remote: RemoteConfig,
         rdir: String,
         ldir: String,
         port: u16,
+        transport: TransportKind,
+        /// Whether the server must be started on the remote host
+        spawn: bool,
+        mtu: usize,
+        retry: RetryPolicy,
+        /// File holding the key shared with the server, traffic is sent in
+        /// the clear when missing
+        key_file: Option<PathBuf>,
+        /// Whether extended attributes are passed through to the server
+        xattr: bool,
     }
 
     pub fn main() {
@@ -54,28 +96,13 @@ mod main {
 
         match arg_parse(env::args().collect()) {
             Ok(config) => {
-                let MofosConfig {
-                    fuse_args,
-                    host,
-                    rdir,
-                    ldir,
-                    port,
-                } = config;
-
-                let spec = format!("{}:{}", host, port);
-                let addr = spec.parse().expect("invalid host specification");
-
-                let fuse: Vec<&OsStr> = fuse_args.iter().map(|x| OsStr::new(x)).collect();
-
-                let fs = MofosFS::new(addr, &rdir);
-                let client = Client::new(addr);
-
-                if let Err(e) = fuse::mount(fs, &ldir, fuse.as_slice()) {
-                    println!("{}", e);
+                // the remote server is stopped by the time this returns
+                if let Err(e) = mount(&config) {
+                    error!("{}", e);
                     process::exit(1);
-                } else {
-                    info!("mofos exiting");
                 }
+
+                info!("mofos exiting");
             }
 
             Err(s) => {
@@ -85,16 +112,190 @@ mod main {
         }
     }
 
+    /// Starts the server if needed, connects to it and serves the mount
+    /// until it is unmounted
+    fn mount(config: &MofosConfig) -> Result<(), String> {
+        let fuse: Vec<&OsStr> = config.fuse_args.iter().map(OsStr::new).collect();
+        let host = &config.remote.host;
+        // in stdio mode the transport itself runs the server
+        let spawn = config.spawn && config.transport != TransportKind::Stdio;
+        let key = session_key(config, spawn)
+            .map_err(|e| format!("unable to setup session key: {}", e))?;
+
+        let mut server = if spawn {
+            let server = spawn_remote_server(
+                &config.remote,
+                config.transport,
+                config.port,
+                config.mtu,
+                &config.rdir,
+                key.as_ref(),
+            )
+            .map_err(|e| format!("unable to start server on {}: {}", host, e))?;
+
+            Some(server)
+        } else {
+            None
+        };
+
+        let (client, session) = open_session(config, key, server.as_mut())?;
+        let fs = MofosFS::new(client, session.has(FEATURE_XATTR));
+
+        fuse::mount(fs, &config.ldir, fuse.as_slice()).map_err(|e| e.to_string())
+    }
+
+    /// Connects to the server and opens the session. The server we started is
+    /// watched meanwhile, its failure is reported along with what it printed
+    /// rather than as a timeout.
+    fn open_session(
+        config: &MofosConfig,
+        key: Option<Key>,
+        mut server: Option<&mut RemoteServer>,
+    ) -> Result<(Client, Hello), String> {
+        let host = config.remote.host.clone();
+        let failed = |e: Error| format!("server on {} failed: {}", host, e);
+        let spawned = server.is_some();
+        let config = config.clone();
+        let opening = thread::spawn(move || {
+            let host = &config.remote.host;
+            let transport = connect(&config, key.as_ref(), spawned)
+                .map_err(|e| format!("unable to reach {}: {}", host, e))?;
+            let mut client = Client::new(transport);
+            let features = if config.xattr { FEATURE_XATTR } else { 0 };
+            let session = client
+                .hello(features)
+                .map_err(|e| format!("unable to open session with {}: {}", host, e))?;
+
+            Ok((client, session))
+        });
+
+        if let Some(ref mut server) = server {
+            while !opening.is_finished() {
+                server.check().map_err(failed)?;
+                thread::sleep(STARTUP_POLL);
+            }
+        }
+
+        let opened = opening
+            .join()
+            .unwrap_or_else(|_| Err(String::from("session setup panicked")));
+
+        // a server failing after the session was set up shows up as a timeout
+        if let (Err(_), Some(server)) = (&opened, server) {
+            server.check().map_err(failed)?;
+        }
+
+        opened
+    }
+
+    /// Key securing the traffic with the server, a fresh one is generated
+    /// for every server we start unless one was configured
+    fn session_key(config: &MofosConfig, spawn: bool) -> Result<Option<Key>, Error> {
+        match config.key_file {
+            Some(ref path) => Key::load(path).map(Some),
+            None if spawn => Key::generate().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Transport to the server, which may still be starting when `spawned`
+    fn connect(
+        config: &MofosConfig,
+        key: Option<&Key>,
+        spawned: bool,
+    ) -> Result<Box<dyn Transport>, Error> {
+        let remote = &config.remote;
+
+        if config.transport == TransportKind::Stdio {
+            let args = remote.server_args(config.transport, config.port, config.mtu, &config.rdir);
+
+            return Ok(Box::new(PipeTransport::spawn(&remote.launcher(), &args)?));
+        }
+
+        let addr = match (remote.host.as_str(), config.port)
+            .to_socket_addrs()?
+            .next()
+        {
+            Some(addr) => addr,
+            None => return Err(Error::new(ErrorKind::NotFound, "unknown host")),
+        };
+
+        match config.transport {
+            TransportKind::Tcp => {
+                let wait = if spawned {
+                    SERVER_STARTUP
+                } else {
+                    Duration::ZERO
+                };
+
+                Ok(Box::new(TcpTransport::connect(addr, key, wait)?))
+            }
+            _ => Ok(Box::new(UdpTransport::connect(
+                addr,
+                config.retry,
+                config.mtu,
+                key,
+            )?)),
+        }
+    }
+
     fn arg_parse(args: Vec<String>) -> Result<MofosConfig, String> {
         let mut fuse_args = Vec::new();
         let mut host = None;
         let mut directory = None;
         let mut local = None;
-        let mut port = Some(22);
-
-        for arg in args {
-            if arg.contains(":") {
-                let split: Vec<&str> = arg.split(":").collect();
+        let mut port = Some(DEFAULT_PORT);
+        let mut transport = TransportKind::default();
+        let mut ssh = vec![String::from("ssh")];
+        let mut ssh_options = Vec::new();
+        let mut binary = String::from(DEFAULT_BINARY);
+        let mut spawn = true;
+        let mut mtu = DEFAULT_MTU;
+        let mut retry = RetryPolicy::default();
+        let mut key_file = None;
+        let mut xattr = true;
+
+        for arg in args.into_iter().skip(1) {
+            if arg.starts_with("-p=") {
+                port = Some(option_value(&arg)?);
+            } else if arg.starts_with("--transport=") {
+                transport = option_value(&arg)?;
+            } else if arg.starts_with("--ssh=") {
+                ssh = option_value::<String>(&arg)?
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect();
+
+                if ssh.is_empty() {
+                    return Err(String::from("empty ssh command"));
+                }
+            } else if arg.starts_with("--ssh-option=") {
+                ssh_options.push(option_value::<String>(&arg)?);
+            } else if arg.starts_with("--ssh-port=") {
+                ssh_options.push(String::from("-p"));
+                ssh_options.push(option_value::<u16>(&arg)?.to_string());
+            } else if arg.starts_with("--server=") {
+                binary = option_value(&arg)?;
+            } else if arg == "--no-spawn" {
+                spawn = false;
+            } else if arg.starts_with("--mtu=") {
+                mtu = option_value(&arg)?;
+
+                if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+                    return Err(format!("mtu must be between {} and {}", MIN_MTU, MAX_MTU));
+                }
+            } else if arg.starts_with("--timeout=") {
+                retry.timeout = Duration::from_millis(option_value(&arg)?);
+            } else if arg.starts_with("--retries=") {
+                retry.retries = option_value(&arg)?;
+            } else if arg.starts_with("--key-file=") {
+                key_file = Some(option_value(&arg)?);
+            } else if arg == "--no-xattr" {
+                xattr = false;
+            } else if arg.starts_with('-') {
+                fuse_args.push(arg);
+            } else if arg.contains(':') {
+                let split: Vec<&str> = arg.split(':').collect();
 
                 if split.len() != 2 {
                     return Err(String::from("invalid host format"));
@@ -102,55 +303,83 @@ mod main {
                     host = Some(String::from(split[0]));
                     directory = Some(String::from(split[1]));
                 }
-            } else if arg.starts_with("-p=") {
-                let v: Vec<&str> = arg.split("=").collect();
-                let p = String::from(v[1]);
-
-                port = Some(p.parse::<u16>().unwrap());
-            } else if arg[0] != "-" {
-                fuse_args.push(String::from(arg));
             } else {
-                local = arg.clone();
+                local = Some(arg);
             }
         }
 
-        let host = host.expect("missing remote host");
-        let local = local.expect("local mountpoint unspecified");
-        let directory = directory.expect("missing remote directory");
-        let port = port.expect("missing destination port");
+        let host = host.ok_or("missing remote host")?;
+        let local = local.ok_or("local mountpoint unspecified")?;
+        let directory = directory.ok_or("missing remote directory")?;
+        let port = port.ok_or("missing destination port")?;
+
+        info!(
+            "mounting {} on {} using remote port {}",
+            directory, local, port
+        );
+
+        ssh.extend(ssh_options);
 
         let config = MofosConfig {
             fuse_args,
-            host,
+            remote: RemoteConfig { ssh, host, binary },
             ldir: local,
             rdir: directory,
             port,
+            transport,
+            spawn,
+            mtu,
+            retry,
+            key_file,
+            xattr,
         };
 
-        info!(
-            "mounting {} on {} using remote port {}",
-            directory, local, port
-        );
-
         Ok(config)
     }
+
+    /// Parses the value of a `--name=value` style argument
+    fn option_value<T: FromStr>(arg: &str) -> Result<T, String> {
+        let value = arg.split_once('=').map_or("", |(_, v)| v);
+
+        value
+            .parse::<T>()
+            .map_err(|_| format!("invalid argument {}", arg))
+    }
 }
 
 #[cfg(not(feature = "client"))]
 mod main {
     use std::env;
-    use std::path::Path;
+    use std::io::{self, Error, ErrorKind, Read};
+    use std::path::{Path, PathBuf};
     use std::process;
+    use std::thread;
 
     use super::common_init;
+    use super::jail;
+    use super::secure::Key;
     use super::server::MofosServer;
+    use super::transport::{
+        Listener, StdioListener, TcpListener, TransportKind, UdpListener, DEFAULT_MTU, MAX_MTU,
+        MIN_MTU,
+    };
 
-    use log::{error, info};
+    use log::{error, info, warn};
 
     #[derive(Default)]
     struct MofosConfig {
         port: u16,
         directory: String,
+        transport: TransportKind,
+        mtu: usize,
+        key_file: Option<PathBuf>,
+        /// Read the key from stdin, as sent by the client that started us
+        key_stdin: bool,
+        /// Exit once stdin is closed by the client that started us
+        watch_stdin: bool,
+        chroot: bool,
+        /// User and group to switch to once setup is complete
+        user: Option<(u32, u32)>,
     }
 
     pub fn main() {
@@ -162,9 +391,32 @@ mod main {
                 let addr = format!("0.0.0.0:{}", config.port)
                     .parse()
                     .expect("bad port: {}");
-                let mut server = MofosServer::new(addr, Path::new(&config.directory))
+                let key = session_key(&config).expect("failed to load key");
+
+                if config.watch_stdin {
+                    watch_stdin();
+                }
+
+                let listener: Box<dyn Listener> = match config.transport {
+                    TransportKind::Udp => Box::new(
+                        UdpListener::bind(addr, config.mtu, key).expect("failed to bind socket"),
+                    ),
+                    TransportKind::Tcp => {
+                        Box::new(TcpListener::bind(addr, key).expect("failed to bind socket"))
+                    }
+                    TransportKind::Stdio => Box::new(StdioListener::new()),
+                };
+                let mut server = MofosServer::new(listener, Path::new(&config.directory))
                     .expect("failed to setup server");
 
+                if config.chroot {
+                    server.chroot().expect("failed to chroot");
+                }
+
+                if let Some((uid, gid)) = config.user {
+                    jail::drop_privileges(uid, gid).expect("failed to drop privileges");
+                }
+
                 match server.run() {
                     Ok(()) => info!("server exited correctly"),
                     Err(e) => error!("server failed: {}", e),
@@ -178,15 +430,61 @@ mod main {
         }
     }
 
+    /// Key every message must be sealed with, anything is accepted when none
+    /// is configured
+    fn session_key(config: &MofosConfig) -> Result<Option<Key>, Error> {
+        if config.key_stdin {
+            let mut line = String::new();
+
+            io::stdin().read_line(&mut line)?;
+
+            return Key::from_hex(&line).map(Some);
+        }
+
+        match config.key_file {
+            Some(ref path) => Key::load(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Exits once stdin reaches its end. The client that started us over ssh
+    /// keeps it open for as long as it runs, sshd closes it when the client
+    /// is gone.
+    fn watch_stdin() {
+        thread::spawn(|| {
+            let mut buf = [0u8; 64];
+
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        warn!("failed to read stdin: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            info!("client is gone, exiting");
+            process::exit(0);
+        });
+    }
+
     fn parse_args(args: Vec<String>) -> Result<MofosConfig, String> {
-        let mut config = MofosConfig::default();
+        let mut config = MofosConfig {
+            mtu: DEFAULT_MTU,
+            ..Default::default()
+        };
 
         if args.len() == 1 {
             usage(&args[0]);
             process::exit(127);
         }
 
-        for mut i in 1..args.len() {
+        let mut i = 1;
+
+        while i < args.len() {
             if args[i] == "-p" || args[i] == "--port" {
                 if i == args.len() - 1 {
                     return Err(String::from("missing required argument for -p"));
@@ -195,25 +493,82 @@ mod main {
                 if let Ok(port) = args[i + 1].parse::<u16>() {
                     config.port = port;
                 } else {
-                    return Err(String::from(format!("invalid port {}", args[i + 1])));
+                    return Err(format!("invalid port {}", args[i + 1]));
                 }
+                i += 1;
             } else if args[i] == "-t" || args[i] == "--target" {
                 if i == args.len() - 1 {
                     return Err(String::from("missing required argument for -t"));
                 }
-                config.directory = String::from(args[i + 1].clone());
+                config.directory = args[i + 1].clone();
+                i += 1;
+            } else if args[i] == "-T" || args[i] == "--transport" {
+                if i == args.len() - 1 {
+                    return Err(String::from("missing required argument for -T"));
+                }
+
+                config.transport = args[i + 1].parse()?;
+                i += 1;
+            } else if args[i] == "-m" || args[i] == "--mtu" {
+                if i == args.len() - 1 {
+                    return Err(String::from("missing required argument for -m"));
+                }
+
+                match args[i + 1].parse::<usize>() {
+                    Ok(mtu) if (MIN_MTU..=MAX_MTU).contains(&mtu) => config.mtu = mtu,
+                    _ => return Err(format!("invalid mtu {}", args[i + 1])),
+                }
+                i += 1;
+            } else if args[i] == "-k" || args[i] == "--key-file" {
+                if i == args.len() - 1 {
+                    return Err(String::from("missing required argument for -k"));
+                }
+
+                config.key_file = Some(PathBuf::from(&args[i + 1]));
+                i += 1;
+            } else if args[i] == "--key-stdin" {
+                config.key_stdin = true;
+            } else if args[i] == "--watch-stdin" {
+                config.watch_stdin = true;
+            } else if args[i] == "-c" || args[i] == "--chroot" {
+                config.chroot = true;
+            } else if args[i] == "-u" || args[i] == "--user" {
+                if i == args.len() - 1 {
+                    return Err(String::from("missing required argument for -u"));
+                }
+
+                let ids = args[i + 1]
+                    .split_once(':')
+                    .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)));
+
+                match ids {
+                    Some(ids) => config.user = Some(ids),
+                    None => return Err(format!("invalid user {}, expected uid:gid", args[i + 1])),
+                }
                 i += 1;
             } else {
-                return Err(String::from(format!("{}", args[i])));
+                return Err(args[i].to_string());
             }
+
+            i += 1;
+        }
+
+        // stdin carries the requests themselves in stdio mode
+        if config.key_stdin && config.transport == TransportKind::Stdio {
+            return Err(String::from("--key-stdin is not supported with stdio"));
+        }
+
+        if config.watch_stdin && config.transport == TransportKind::Stdio {
+            return Err(String::from("--watch-stdin is not supported with stdio"));
         }
 
         Ok(config)
     }
 
-    fn usage(pname: &String) {
+    fn usage(pname: &str) {
         println!(
-            "you should not run this manually, the server is supposed to be started by the client"
+            "{}: you should not run this manually, the server is supposed to be started by the client",
+            pname
         );
     }
 }
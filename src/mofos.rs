@@ -1,123 +1,926 @@
 extern crate fuse;
 extern crate libc;
+extern crate time;
 
-use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::net::SocketAddr;
+use std::path::Path;
 
 use self::fuse::*;
 use self::libc::c_int;
+use self::time::Timespec;
 
-enum MofosData {
-    Dir(String, u16),
-    File(String, u16),
+use super::client::{Client, KEEPALIVE_INTERVAL};
+use super::inode::InodeTable;
+use super::proto::{MofosRequest, MofosResponse, SetAttrs, Status, Timestamp, Xattr};
+
+/// Time the kernel may cache the attributes and entries we reply with
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+pub struct MofosFS {
+    client: Client,
+    inodes: InodeTable,
+    /// Whether extended attributes are passed through to the server
+    xattr: bool,
 }
 
-impl MofosData {
-    fn new_file(path: String, perms: u16) -> MofosData {
-        MofosData::File(path, perms)
+impl MofosFS {
+    pub fn new(client: Client, xattr: bool) -> MofosFS {
+        MofosFS {
+            client,
+            inodes: InodeTable::new(),
+            xattr,
+        }
     }
 
-    fn new_dir(path: String, perms: u16) -> MofosData {
-        MofosData::Dir(path, perms)
+    /// Remote path of `ino` and the attribute `name`, `ENOSYS` when extended
+    /// attributes are disabled so that the kernel stops asking for them
+    fn xattr_name(&self, ino: u64, name: &OsStr) -> Result<(String, String), c_int> {
+        if !self.xattr {
+            return Err(libc::ENOSYS);
+        }
+
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+
+        Ok((self.path(ino)?, String::from(name)))
     }
 
-    fn path(&self) -> &String {
-        match self {
-            MofosData::File(path, _) => path,
-            MofosData::Dir(path, _) => path,
-        }
+    /// Sends a request to the server, transport failures such as exhausting
+    /// all retransmissions are reported to the kernel as `EIO`
+    fn request(&mut self, req: MofosRequest) -> Result<MofosResponse, c_int> {
+        self.client.send_req(req).map_err(|e| {
+            error!("request failed: {}", e);
+            libc::EIO
+        })
     }
 
-    fn perms(&self) -> u16 {
-        match self {
-            MofosData::File(_, perms) => *perms,
-            MofosData::Dir(_, perms) => *perms,
-        }
+    /// Remote path of the inode `ino`, relative to the exported directory
+    fn path(&self, ino: u64) -> Result<String, c_int> {
+        self.inodes.path(ino).ok_or_else(|| {
+            warn!("unknown inode {}", ino);
+            libc::ENOENT
+        })
     }
-}
 
-pub struct MofosFS {
-    addr: SocketAddr,
-    last_ino: u64,
-    inomap: HashMap<u64, MofosData>,
-    fhs: HashMap<u64, MofosData>,
-}
+    /// Remote path of the directory `parent` along with `name`, the entry of
+    /// it a request is about
+    fn entry(&self, parent: u64, name: &OsStr) -> Result<(String, String), c_int> {
+        let name = name.to_str().ok_or(libc::EINVAL)?;
 
-impl MofosFS {
-    pub fn new(addr: SocketAddr, dir: &str) -> MofosFS {
-        MofosFS {
-            addr: addr,
-            inomap: HashMap::new(),
-            fhs: HashMap::new(),
-            last_ino: 1,
-        }
+        Ok((self.path(parent)?, String::from(name)))
     }
 
-    fn path_from_ino(&self, ino: u64) -> &String {
-        if let Some(entry) = self.inomap.get(&ino) {
-            match entry {
-                MofosData::File(path, perms) => &path,
-                MofosData::Dir(path, perms) => &path,
+    /// Reads `size` bytes at `offset` of the file opened as `fh`, a single
+    /// response can only carry so much so larger reads are split
+    fn read_range(&mut self, fh: u64, offset: i64, size: usize) -> Result<Vec<u8>, c_int> {
+        let mut data = Vec::with_capacity(size);
+
+        while data.len() < size {
+            let chunk = (size - data.len()).min(self.client.max_read());
+            let req = MofosRequest::Read {
+                id: self.client.next_id(),
+                fh,
+                size: chunk as u32,
+                offset: offset + data.len() as i64,
+            };
+
+            match self.request(req)? {
+                MofosResponse::Read(_, Status::Ok, chunk_data) => {
+                    let eof = chunk_data.len() < chunk;
+
+                    data.extend_from_slice(&chunk_data);
+
+                    if eof {
+                        break;
+                    }
+                }
+
+                MofosResponse::Read(_, status, _) => return Err(status.errno()),
+
+                resp => return Err(unexpected(resp)),
             }
-        } else {
-            panic!("invalid ino");
         }
+
+        Ok(data)
     }
 }
 
+/// Replies to a size probe with the size of the value and otherwise with the
+/// value itself
+fn reply_xattr(reply: ReplyXattr, value: Xattr) {
+    match value {
+        Xattr::Size(size) => reply.size(size),
+        Xattr::Data(data) => reply.data(&data),
+    }
+}
+
+/// Reports a response that does not match the request that was sent
+fn unexpected(resp: MofosResponse) -> c_int {
+    error!("unexpected response to request {}", resp.id());
+
+    libc::EIO
+}
+
 impl Filesystem for MofosFS {
     fn init(&mut self, _req: &Request) -> Result<(), c_int> {
         info!("initializing fuse...");
 
-        self.inomap
-            .insert(self.last_ino, MofosData::new_dir(String::from("/"), 0755));
+        self.client.keepalive(KEEPALIVE_INTERVAL);
 
         Ok(())
     }
 
- fn lookup(&mut self, _req: &Request, parent: u64,
-              name: &OsStr, reply: ReplyEntry)
-    {
-        let s = name.to_str().unwrap();
+    // the server closes whatever we left open, without waiting for timeouts
+    fn destroy(&mut self, _req: &Request) {
+        let req = MofosRequest::Exit {
+            id: self.client.next_id(),
+        };
+
+        if let Err(e) = self.client.notify(req) {
+            warn!("failed to tell the server we are leaving: {}", e);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("lookup {} in {:?}", name, parent_path);
+
+        let req = MofosRequest::Lookup {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Lookup(_, Status::Ok, attrs)) => {
+                let ino = self.inodes.lookup(parent, &name, attrs.file_id());
+
+                reply.entry(&TTL, &attrs.to_fuse(ino), 0);
+            }
+
+            Ok(MofosResponse::Lookup(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+        fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(e) => return reply.error(e),
+        };
+
+        let attrs = SetAttrs {
+            mode,
+            uid,
+            gid,
+            size,
+            atime: atime.map(Timestamp::from),
+            mtime: mtime.map(Timestamp::from),
+        };
+
+        debug!("setattr {:?} with {:?}", path, attrs);
+
+        let req = MofosRequest::SetAttr {
+            id: self.client.next_id(),
+            path,
+            fh,
+            attrs,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::SetAttr(_, Status::Ok, attrs)) => {
+                reply.attr(&TTL, &attrs.to_fuse(ino));
+            }
+
+            Ok(MofosResponse::SetAttr(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("readlink {:?}", path);
+
+        let req = MofosRequest::ReadLink {
+            id: self.client.next_id(),
+            path,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::ReadLink(_, Status::Ok, target)) => reply.data(target.as_bytes()),
+
+            Ok(MofosResponse::ReadLink(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("mknod {} in {:?} with mode {:#o}", name, parent_path, mode);
+
+        let req = MofosRequest::MkNod {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+            mode,
+            rdev,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::MkNod(_, Status::Ok, attrs)) => {
+                let ino = self.inodes.lookup(parent, &name, attrs.file_id());
+
+                reply.entry(&TTL, &attrs.to_fuse(ino), 0);
+            }
+
+            Ok(MofosResponse::MkNod(_, status, _)) => reply.error(status.errno()),
 
-        info!("lookup {}", s);
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
     }
 
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
-        error!("readlink: unimplemented");
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("mkdir {} in {:?} with mode {:#o}", name, parent_path, mode);
+
+        let req = MofosRequest::MkDir {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+            mode,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::MkDir(_, Status::Ok, attrs)) => {
+                let ino = self.inodes.lookup(parent, &name, attrs.file_id());
+
+                reply.entry(&TTL, &attrs.to_fuse(ino), 0);
+            }
+
+            Ok(MofosResponse::MkDir(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
     }
 
-    fn mknod(&mut self, _req: &Request, _parent: u64, _name: &OsStr,
-             _mode: u32, _rdev: u32, _reply: ReplyEntry) {
-        panic!("mknod");
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("unlink {} in {:?}", name, parent_path);
+
+        let req = MofosRequest::Unlink {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Unlink(_, Status::Ok)) => {
+                self.inodes.unlink(parent, &name);
+                reply.ok();
+            }
+
+            Ok(MofosResponse::Unlink(_, status)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
     }
 
-    fn mkdir(&mut self,
-             _req: &Request,
-             _parent: u64,
-             _name: &OsStr,
-             _mode: u32,
-             _reply: ReplyEntry) {
-        panic!("mkdir");
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("rmdir {} in {:?}", name, parent_path);
+
+        let req = MofosRequest::Rmdir {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Rmdir(_, Status::Ok)) => {
+                self.inodes.unlink(parent, &name);
+                reply.ok();
+            }
+
+            Ok(MofosResponse::Rmdir(_, status)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+        let target = match link.to_str() {
+            Some(target) => String::from(target),
+            None => return reply.error(libc::EINVAL),
+        };
+
+        debug!("symlink {} in {:?} to {:?}", name, parent_path, target);
+
+        let req = MofosRequest::Symlink {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+            target,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Symlink(_, Status::Ok, attrs)) => {
+                let ino = self.inodes.lookup(parent, &name, attrs.file_id());
+
+                reply.entry(&TTL, &attrs.to_fuse(ino), 0);
+            }
+
+            Ok(MofosResponse::Symlink(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let entries = self
+            .path(ino)
+            .and_then(|path| Ok((path, self.entry(newparent, newname)?)));
+        let (path, (newparent_path, newname)) = match entries {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("link {} in {:?} to {:?}", newname, newparent_path, path);
+
+        let req = MofosRequest::Link {
+            id: self.client.next_id(),
+            path,
+            newparent: newparent_path,
+            newname: newname.clone(),
+        };
+
+        match self.request(req) {
+            // the new name shares the inode of the file it links to
+            Ok(MofosResponse::Link(_, Status::Ok, attrs)) => {
+                let ino = self.inodes.lookup(newparent, &newname, attrs.file_id());
+
+                reply.entry(&TTL, &attrs.to_fuse(ino), 0);
+            }
+
+            Ok(MofosResponse::Link(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // the kernel interface of this version of fuse does not pass rename
+    // flags, renames through the mount always replace their destination
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let entries = self
+            .entry(parent, name)
+            .and_then(|from| Ok((from, self.entry(newparent, newname)?)));
+        let ((parent_path, name), (newparent_path, newname)) = match entries {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!(
+            "rename {} in {:?} to {} in {:?}",
+            name, parent_path, newname, newparent_path
+        );
+
+        let req = MofosRequest::Rename {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+            newparent: newparent_path,
+            newname: newname.clone(),
+            flags: 0,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Rename(_, Status::Ok)) => {
+                self.inodes.rename(parent, &name, newparent, &newname);
+                reply.ok();
+            }
+
+            Ok(MofosResponse::Rename(_, status)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        info!("getattr for {}", ino);
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("getattr {:?} for inode {}", path, ino);
+
+        let req = MofosRequest::GetAttr {
+            id: self.client.next_id(),
+            path,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::GetAttr(_, Status::Ok, attrs)) => {
+                reply.attr(&TTL, &attrs.to_fuse(ino));
+            }
+
+            Ok(MofosResponse::GetAttr(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(e) => return reply.error(e),
+        };
 
-        let path = self.path_from_ino(ino);
+        debug!("open {:?} with flags {:#o}", path, flags);
 
-        info!("found path {} for ino {}", path, ino);
+        let req = MofosRequest::Open {
+            id: self.client.next_id(),
+            path,
+            flags,
+        };
+
+        // the kernel refers to the file by the server's handle
+        match self.request(req) {
+            Ok(MofosResponse::Open(_, Status::Ok, fh)) => reply.opened(fh, 0),
+
+            Ok(MofosResponse::Open(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        match self.read_range(fh, offset, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let (parent_path, name) = match self.entry(parent, name) {
+            Ok(entry) => entry,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("create {} in {:?} with mode {:#o}", name, parent_path, mode);
+
+        let req = MofosRequest::Create {
+            id: self.client.next_id(),
+            parent: parent_path,
+            name: name.clone(),
+            mode,
+            flags,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Create(_, Status::Ok, attrs, fh)) => {
+                let ino = self.inodes.lookup(parent, &name, attrs.file_id());
+
+                reply.created(&TTL, &attrs.to_fuse(ino), 0, fh, 0);
+            }
+
+            Ok(MofosResponse::Create(_, status, ..)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let req = MofosRequest::Write {
+            id: self.client.next_id(),
+            fh,
+            data: Vec::from(data),
+            offset,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Write(_, Status::Ok, written)) => reply.written(written),
+            Ok(MofosResponse::Write(_, status, _)) => reply.error(status.errno()),
+            Ok(resp) => reply.error(unexpected(resp)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let req = MofosRequest::Flush {
+            id: self.client.next_id(),
+            fh,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Flush(_, Status::Ok)) => reply.ok(),
+            Ok(MofosResponse::Flush(_, status)) => reply.error(status.errno()),
+            Ok(resp) => reply.error(unexpected(resp)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let req = MofosRequest::Fsync {
+            id: self.client.next_id(),
+            fh,
+            datasync,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Fsync(_, Status::Ok)) => reply.ok(),
+            Ok(MofosResponse::Fsync(_, status)) => reply.error(status.errno()),
+            Ok(resp) => reply.error(unexpected(resp)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let req = MofosRequest::Release {
+            id: self.client.next_id(),
+            fh,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::Release(_, Status::Ok)) => reply.ok(),
+            Ok(MofosResponse::Release(_, status)) => reply.error(status.errno()),
+            Ok(resp) => reply.error(unexpected(resp)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let req = MofosRequest::StatFs {
+            id: self.client.next_id(),
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::StatFs(_, Status::Ok, stats)) => stats.reply(reply),
+
+            Ok(MofosResponse::StatFs(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (path, name) = match self.xattr_name(ino, name) {
+            Ok(xattr) => xattr,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("setxattr {} of {:?}", name, path);
+
+        let req = MofosRequest::SetXattr {
+            id: self.client.next_id(),
+            path,
+            name,
+            value: value.to_vec(),
+            flags,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::SetXattr(_, Status::Ok)) => reply.ok(),
+
+            Ok(MofosResponse::SetXattr(_, status)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let (path, name) = match self.xattr_name(ino, name) {
+            Ok(xattr) => xattr,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("getxattr {} of {:?} with size {}", name, path, size);
+
+        let req = MofosRequest::GetXattr {
+            id: self.client.next_id(),
+            path,
+            name,
+            size,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::GetXattr(_, Status::Ok, value)) => reply_xattr(reply, value),
+
+            Ok(MofosResponse::GetXattr(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        if !self.xattr {
+            return reply.error(libc::ENOSYS);
+        }
+
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("listxattr {:?} with size {}", path, size);
+
+        let req = MofosRequest::ListXattr {
+            id: self.client.next_id(),
+            path,
+            size,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::ListXattr(_, Status::Ok, names)) => reply_xattr(reply, names),
+
+            Ok(MofosResponse::ListXattr(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (path, name) = match self.xattr_name(ino, name) {
+            Ok(xattr) => xattr,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("removexattr {} of {:?}", name, path);
+
+        let req = MofosRequest::RemoveXattr {
+            id: self.client.next_id(),
+            path,
+            name,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::RemoveXattr(_, Status::Ok)) => reply.ok(),
+
+            Ok(MofosResponse::RemoveXattr(_, status)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
     }
 
     fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        info!("opening dir {}", ino);
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(e) => return reply.error(e),
+        };
+
+        debug!("opendir {:?}", path);
+
+        let req = MofosRequest::OpenDir {
+            id: self.client.next_id(),
+            path,
+            flags,
+        };
 
-        reply.opened(0, flags)
+        match self.request(req) {
+            Ok(MofosResponse::OpenDir(_, Status::Ok, fh)) => reply.opened(fh, 0),
+
+            Ok(MofosResponse::OpenDir(_, status, _)) => reply.error(status.errno()),
+
+            Ok(resp) => reply.error(unexpected(resp)),
+
+            Err(e) => reply.error(e),
+        }
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64,
-               offset: i64, reply: ReplyDirectory) {
-        info!("reading directory {}", ino);
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!("readdir {} from offset {}", fh, offset);
+
+        // `.` and `..` take the first two offsets, those of the server's
+        // entries are shifted past them
+        let mut offset = offset;
+
+        if offset == 0 {
+            if reply.add(ino, 1, FileType::Directory, ".") {
+                return reply.ok();
+            }
+
+            offset = 1;
+        }
+
+        if offset == 1 {
+            let parent = self.inodes.parent(ino).unwrap_or(ino);
+
+            if reply.add(parent, 2, FileType::Directory, "..") {
+                return reply.ok();
+            }
+
+            offset = 2;
+        }
+
+        // keep fetching pages until the kernel's buffer is full
+        loop {
+            let req = MofosRequest::Readdir {
+                id: self.client.next_id(),
+                fh,
+                offset: offset - 2,
+            };
+
+            let entries = match self.request(req) {
+                Ok(MofosResponse::Readdir(_, Status::Ok, entries)) => entries,
+                Ok(MofosResponse::Readdir(_, status, _)) => return reply.error(status.errno()),
+                Ok(resp) => return reply.error(unexpected(resp)),
+                Err(e) => return reply.error(e),
+            };
+
+            if entries.is_empty() {
+                return reply.ok();
+            }
+
+            for entry in entries {
+                // inode numbers in a listing are only advisory, entries the
+                // kernel did not look up get the server's rather than one
+                // allocated here and never forgotten
+                let entry_ino = self.inodes.find(ino, entry.name()).unwrap_or(entry.ino());
+
+                offset = entry.offset() + 2;
+
+                if reply.add(entry_ino, offset, entry.kind().into(), entry.name()) {
+                    return reply.ok();
+                }
+            }
+        }
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        let req = MofosRequest::ReleaseDir {
+            id: self.client.next_id(),
+            fh,
+        };
+
+        match self.request(req) {
+            Ok(MofosResponse::ReleaseDir(_, Status::Ok)) => reply.ok(),
+            Ok(MofosResponse::ReleaseDir(_, status)) => reply.error(status.errno()),
+            Ok(resp) => reply.error(unexpected(resp)),
+            Err(e) => reply.error(e),
+        }
     }
 }
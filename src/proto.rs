@@ -1,39 +1,284 @@
 extern crate bincode;
+#[cfg(feature = "client")]
+extern crate fuse;
+extern crate libc;
+#[cfg(feature = "client")]
+extern crate time;
 
 use std::convert::{TryFrom, TryInto};
-use std::fs::{DirEntry, FileType, Metadata};
-use std::os::unix::fs::{DirEntryExt, FileTypeExt, MetadataExt};
+use std::fs::{FileType, Metadata};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 
-use self::bincode::{deserialize, serialize, ErrorKind};
+use self::bincode::{deserialize, serialize, serialized_size};
+use self::libc::c_int;
 
-#[repr(u8)]
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// Outcome of a request, failures carry the errno the operation failed with
+/// on the server so that applications see the error they would locally.
+/// Error numbers are those of the server's platform.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum Status {
-    Ok = 0,
-    NotFound = 1,
-    Denied = 2,
-    IOError = 3,
+    Ok,
+    Err(i32),
+}
 
-    Unknown = 0xff,
+impl Status {
+    /// Error number reported to the kernel for this status
+    pub fn errno(&self) -> c_int {
+        match *self {
+            Status::Ok => 0,
+            // a failure must never be mistaken for a success
+            Status::Err(0) => libc::EIO,
+            Status::Err(errno) => errno,
+        }
+    }
+}
+
+/// Error number closest to an error that did not come from the system
+fn kind_errno(kind: io::ErrorKind) -> c_int {
+    match kind {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        io::ErrorKind::AlreadyExists => libc::EEXIST,
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        io::ErrorKind::Unsupported => libc::ENOTSUP,
+        io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        io::ErrorKind::OutOfMemory => libc::ENOMEM,
+        _ => libc::EIO,
+    }
+}
+
+impl<'a> From<&'a io::Error> for Status {
+    fn from(e: &'a io::Error) -> Self {
+        Status::Err(e.raw_os_error().unwrap_or_else(|| kind_errno(e.kind())))
+    }
+}
+
+/// Largest amount of data transferred by a single read
+pub const MAX_READ: usize = 1 << 20;
+
+/// Version of the protocol, bumped on every change to the requests or the
+/// responses since peers speaking different versions cannot decode each
+/// other's messages
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Optional features a peer supports, as bits of `Hello::features`
+pub const FEATURE_XATTR: u64 = 1 << 0;
+
+/// Room a read response needs besides the data read
+const READ_HEADROOM: usize = 4 << 10;
+
+/// Opening of a session, each peer states what it is able to handle
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct Hello {
+    pub version: u32,
+    pub features: u64,
+    /// Largest serialized message the peer accepts
+    pub max_message: u32,
+    /// Picked at random by the client for each mount, the server forgets
+    /// what an earlier mount left behind when it sees a new one
+    pub session: u64,
+}
+
+impl Hello {
+    /// What is available to both ends of a session whose peers sent `self`
+    /// and `other`
+    pub fn negotiate(&self, other: &Hello) -> Hello {
+        Hello {
+            version: self.version.min(other.version),
+            features: self.features & other.features,
+            max_message: self.max_message.min(other.max_message),
+            session: self.session,
+        }
+    }
+
+    pub fn has(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// Largest read whose response fits in a message
+    pub fn max_read(&self) -> usize {
+        MAX_READ.min((self.max_message as usize).saturating_sub(READ_HEADROOM))
+    }
+}
+
+/// Path of the entry `name` of the directory `parent`, paths are relative to
+/// the exported directory which is itself the empty path
+#[cfg(not(feature = "client"))]
+pub fn child_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// Marks the start of every message, "MOFS"
+const MAGIC: u32 = 0x4d4f_4653;
+
+/// Version of the envelope itself, the messages it carries are versioned by
+/// `PROTOCOL_VERSION`
+const ENVELOPE_VERSION: u8 = 1;
+
+/// Size of the envelope in front of every message
+const ENVELOPE_SIZE: usize = 23;
+
+/// Kind of the message carried by an envelope
+const KIND_REQUEST: u8 = 1;
+const KIND_RESPONSE: u8 = 2;
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Table driven CRC-32 as used by ethernet and zlib
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+/// Header framing every serialized request and response so that corrupt or
+/// foreign data is rejected before being deserialized. It is made of, in
+/// network byte order, the magic number, the envelope version, the kind of
+/// message, flags which are all reserved, the id of the message, the length
+/// of the payload and a CRC-32 of all of these and the payload. The
+/// transports authenticate messages when a key is configured, the checksum
+/// only catches accidents.
+struct Envelope<'a> {
+    id: u64,
+    payload: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn encode(id: u64, kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(ENVELOPE_SIZE + payload.len());
+
+        message.extend_from_slice(&MAGIC.to_be_bytes());
+        message.push(ENVELOPE_VERSION);
+        message.push(kind);
+        message.push(0);
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+
+        let crc = crc32(&[&message, payload]);
+
+        message.extend_from_slice(&crc.to_be_bytes());
+        message.extend_from_slice(payload);
+
+        message
+    }
+
+    /// Checks the envelope of `message`, which must be of `kind`
+    fn decode(message: &'a [u8], kind: u8) -> Result<Envelope<'a>, io::Error> {
+        if message.len() < ENVELOPE_SIZE {
+            return Err(invalid("message shorter than its envelope"));
+        }
+
+        let (header, payload) = message.split_at(ENVELOPE_SIZE);
+        let u32_at = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+
+        if u32_at(0) != MAGIC {
+            return Err(invalid("bad magic number"));
+        }
+
+        if header[4] != ENVELOPE_VERSION {
+            return Err(invalid(format!("unknown envelope version {}", header[4])));
+        }
+
+        if u32_at(15) as usize != payload.len() {
+            return Err(invalid("payload length mismatch"));
+        }
+
+        if u32_at(19) != crc32(&[&header[..19], payload]) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        if header[5] != kind {
+            return Err(invalid(format!("unexpected message kind {}", header[5])));
+        }
+
+        Ok(Envelope {
+            id: u64::from_be_bytes(header[7..15].try_into().unwrap()),
+            payload,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum MofosRequest {
+    /// Opens the session, this must remain the first variant and keep its
+    /// fields for peers of any version to understand each other that far
+    Hello {
+        id: u64,
+        hello: Hello,
+    },
+
     GetAttr {
         id: u64,
         path: String,
     },
+    /// Looks up `name` in the directory `parent`
+    Lookup {
+        id: u64,
+        parent: String,
+        name: String,
+    },
+    /// Changes the attributes of the file at `path` itself, symbolic links
+    /// are not followed. The size is set through `fh` when the file is open.
     SetAttr {
         id: u64,
         path: String,
-        attrs: FileAttr,
+        fh: Option<u64>,
+        attrs: SetAttrs,
     },
 
+    /// Opens the file at `path`, the response carries the handle it is then
+    /// referred to by
     Open {
         id: u64,
         path: String,
         flags: u32,
     },
+    /// Creates and opens the file `name` in the directory `parent`
+    Create {
+        id: u64,
+        parent: String,
+        name: String,
+        mode: u32,
+        flags: u32,
+    },
     OpenDir {
         id: u64,
         path: String,
@@ -41,77 +286,324 @@ pub enum MofosRequest {
     },
     Readdir {
         id: u64,
-        path: String,
+        fh: u64,
         offset: i64,
     },
+    /// Closes the directory opened by a previous `OpenDir`
+    ReleaseDir {
+        id: u64,
+        fh: u64,
+    },
 
+    /// Creates the file node `name` in the directory `parent`
     MkNod {
+        id: u64,
+        parent: String,
+        name: String,
+        mode: u32,
+        rdev: u32,
+    },
+    MkDir {
+        id: u64,
+        parent: String,
+        name: String,
+        mode: u32,
+    },
+    Unlink {
+        id: u64,
+        parent: String,
+        name: String,
+    },
+    Rmdir {
+        id: u64,
+        parent: String,
+        name: String,
+    },
+    /// Renames `name` in `parent` to `newname` in `newparent`, `flags` are
+    /// those of renameat2(2)
+    Rename {
+        id: u64,
+        parent: String,
+        name: String,
+        newparent: String,
+        newname: String,
+        flags: u32,
+    },
+
+    /// Reads the extended attribute `name` of `path`, only its size is
+    /// returned when `size` is 0
+    GetXattr {
+        id: u64,
+        path: String,
+        name: String,
+        size: u32,
+    },
+    /// `flags` are those of setxattr(2)
+    SetXattr {
         id: u64,
         path: String,
+        name: String,
+        value: Vec<u8>,
         flags: u32,
     },
-    MkDir {
+    /// Lists the names of the extended attributes of `path`, only the size
+    /// of the list is returned when `size` is 0
+    ListXattr {
         id: u64,
         path: String,
-        mode: u32,
+        size: u32,
+    },
+    RemoveXattr {
+        id: u64,
+        path: String,
+        name: String,
     },
 
-    Write {
+    /// Reports the capacity of the filesystem holding the exported directory
+    StatFs {
+        id: u64,
+    },
+
+    /// Reads the target of the symbolic link at `path`
+    ReadLink {
         id: u64,
         path: String,
+    },
+    /// Creates the symbolic link `name` in `parent` pointing to `target`,
+    /// which is stored as is
+    Symlink {
+        id: u64,
+        parent: String,
+        name: String,
+        target: String,
+    },
+    /// Creates `newname` in `newparent` as a hard link to `path`
+    Link {
+        id: u64,
+        path: String,
+        newparent: String,
+        newname: String,
+    },
+
+    Write {
+        id: u64,
+        fh: u64,
         data: Vec<u8>,
         offset: i64,
     },
     Read {
         id: u64,
-        path: String,
+        fh: u64,
         size: u32,
         offset: i64,
     },
-    Unlink {
+    /// Called on every close of an open file
+    Flush {
         id: u64,
-        path: String,
+        fh: u64,
+    },
+    Fsync {
+        id: u64,
+        fh: u64,
+        /// Only the data needs to be written, not the metadata
+        datasync: bool,
+    },
+    /// Closes the file opened by a previous `Open` or `Create`
+    Release {
+        id: u64,
+        fh: u64,
     },
 
-    Exit,
+    /// Sent by an idle client for the server to keep its handles open
+    KeepAlive {
+        id: u64,
+    },
+    /// Tells the server the client is leaving, the handles it left open are
+    /// closed. No response is sent.
+    Exit {
+        id: u64,
+    },
 }
 
 impl MofosRequest {
-    pub fn new_open(id: u64, path: String, flags: u32) -> MofosRequest {
-        MofosRequest::Open { id, path, flags }
+    /// Identifier the matching `MofosResponse` will carry
+    pub fn id(&self) -> u64 {
+        match *self {
+            MofosRequest::Hello { id, .. }
+            | MofosRequest::GetAttr { id, .. }
+            | MofosRequest::Lookup { id, .. }
+            | MofosRequest::SetAttr { id, .. }
+            | MofosRequest::Open { id, .. }
+            | MofosRequest::Create { id, .. }
+            | MofosRequest::OpenDir { id, .. }
+            | MofosRequest::Readdir { id, .. }
+            | MofosRequest::ReleaseDir { id, .. }
+            | MofosRequest::MkNod { id, .. }
+            | MofosRequest::MkDir { id, .. }
+            | MofosRequest::Write { id, .. }
+            | MofosRequest::Read { id, .. }
+            | MofosRequest::Unlink { id, .. }
+            | MofosRequest::Rmdir { id, .. }
+            | MofosRequest::Rename { id, .. }
+            | MofosRequest::ReadLink { id, .. }
+            | MofosRequest::Symlink { id, .. }
+            | MofosRequest::Link { id, .. }
+            | MofosRequest::GetXattr { id, .. }
+            | MofosRequest::SetXattr { id, .. }
+            | MofosRequest::ListXattr { id, .. }
+            | MofosRequest::RemoveXattr { id, .. }
+            | MofosRequest::StatFs { id }
+            | MofosRequest::Flush { id, .. }
+            | MofosRequest::Fsync { id, .. }
+            | MofosRequest::Release { id, .. }
+            | MofosRequest::KeepAlive { id }
+            | MofosRequest::Exit { id } => id,
+        }
+    }
+
+    /// Whether the server answers this request
+    pub fn expects_response(&self) -> bool {
+        !matches!(*self, MofosRequest::Exit { .. })
     }
 
-    pub fn new_readdir(id: u64, path: String, offset: i64) -> MofosRequest {
-        MofosRequest::Readdir { id, path, offset }
+    /// Whether executing this request twice has the same effect as executing
+    /// it once, only the other requests need their response to be cached for
+    /// retransmissions
+    #[cfg(not(feature = "client"))]
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            *self,
+            MofosRequest::Hello { .. }
+                | MofosRequest::GetAttr { .. }
+                | MofosRequest::Lookup { .. }
+                | MofosRequest::Readdir { .. }
+                | MofosRequest::ReadLink { .. }
+                | MofosRequest::GetXattr { .. }
+                | MofosRequest::ListXattr { .. }
+                | MofosRequest::StatFs { .. }
+                | MofosRequest::Read { .. }
+                | MofosRequest::Flush { .. }
+                | MofosRequest::Fsync { .. }
+                | MofosRequest::KeepAlive { .. }
+        )
+    }
+
+    /// Response reporting that the request failed with `status`, there is
+    /// none for the requests that do not expect one
+    #[cfg(not(feature = "client"))]
+    pub fn failure(&self, status: Status) -> Option<MofosResponse> {
+        let id = self.id();
+        let resp = match *self {
+            MofosRequest::Hello { .. } => MofosResponse::Hello(id, status, Hello::default()),
+            MofosRequest::GetAttr { .. } => MofosResponse::GetAttr(id, status, FileAttr::default()),
+            MofosRequest::Lookup { .. } => MofosResponse::Lookup(id, status, FileAttr::default()),
+            MofosRequest::SetAttr { .. } => MofosResponse::SetAttr(id, status, FileAttr::default()),
+            MofosRequest::Open { .. } => MofosResponse::Open(id, status, 0),
+            MofosRequest::Create { .. } => {
+                MofosResponse::Create(id, status, FileAttr::default(), 0)
+            }
+            MofosRequest::OpenDir { .. } => MofosResponse::OpenDir(id, status, 0),
+            MofosRequest::Readdir { .. } => MofosResponse::Readdir(id, status, Vec::new()),
+            MofosRequest::ReleaseDir { .. } => MofosResponse::ReleaseDir(id, status),
+            MofosRequest::MkNod { .. } => MofosResponse::MkNod(id, status, FileAttr::default()),
+            MofosRequest::MkDir { .. } => MofosResponse::MkDir(id, status, FileAttr::default()),
+            MofosRequest::Unlink { .. } => MofosResponse::Unlink(id, status),
+            MofosRequest::Rmdir { .. } => MofosResponse::Rmdir(id, status),
+            MofosRequest::Rename { .. } => MofosResponse::Rename(id, status),
+            MofosRequest::GetXattr { .. } => MofosResponse::GetXattr(id, status, Xattr::default()),
+            MofosRequest::SetXattr { .. } => MofosResponse::SetXattr(id, status),
+            MofosRequest::ListXattr { .. } => {
+                MofosResponse::ListXattr(id, status, Xattr::default())
+            }
+            MofosRequest::RemoveXattr { .. } => MofosResponse::RemoveXattr(id, status),
+            MofosRequest::StatFs { .. } => MofosResponse::StatFs(id, status, FsStats::default()),
+            MofosRequest::ReadLink { .. } => MofosResponse::ReadLink(id, status, String::new()),
+            MofosRequest::Symlink { .. } => MofosResponse::Symlink(id, status, FileAttr::default()),
+            MofosRequest::Link { .. } => MofosResponse::Link(id, status, FileAttr::default()),
+            MofosRequest::Write { .. } => MofosResponse::Write(id, status, 0),
+            MofosRequest::Read { .. } => MofosResponse::Read(id, status, Vec::new()),
+            MofosRequest::Flush { .. } => MofosResponse::Flush(id, status),
+            MofosRequest::Fsync { .. } => MofosResponse::Fsync(id, status),
+            MofosRequest::Release { .. } => MofosResponse::Release(id, status),
+            MofosRequest::KeepAlive { .. } => MofosResponse::KeepAlive(id, status),
+            MofosRequest::Exit { .. } => return None,
+        };
+
+        Some(resp)
     }
 }
 
 impl<'a> TryFrom<&'a [u8]> for MofosRequest {
-    type Error = Box<ErrorKind>;
+    type Error = io::Error;
 
     fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
-        deserialize(data)
+        let envelope = Envelope::decode(data, KIND_REQUEST)?;
+
+        let req: MofosRequest = deserialize(envelope.payload).map_err(invalid)?;
+
+        if req.id() != envelope.id {
+            return Err(invalid("request id does not match its envelope"));
+        }
+
+        Ok(req)
     }
 }
 
 impl TryInto<Vec<u8>> for MofosRequest {
-    type Error = Box<ErrorKind>;
+    type Error = io::Error;
 
     fn try_into(self) -> Result<Vec<u8>, Self::Error> {
-        serialize(&self)
+        let payload = serialize(&self).map_err(invalid)?;
+
+        Ok(Envelope::encode(self.id(), KIND_REQUEST, &payload))
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum MofosResponse {
+    /// What the server handles, also sent when refusing the session so that
+    /// the client can tell why. This must remain the first variant.
+    Hello(u64, Status, Hello),
+
     GetAttr(u64, Status, FileAttr),
-    SetAttr(u64, Status),
+    /// Carries the attributes of the file once they were changed
+    SetAttr(u64, Status, FileAttr),
 
     Lookup(u64, Status, FileAttr),
-    Open(u64, Status),
+    /// Handle of the opened file
+    Open(u64, Status, u64),
+    Create(u64, Status, FileAttr, u64),
+    OpenDir(u64, Status, u64),
 
     Read(u64, Status, Vec<u8>),
+    /// Number of bytes written
+    Write(u64, Status, u32),
     Readdir(u64, Status, Vec<Entry>),
+    ReleaseDir(u64, Status),
+    Flush(u64, Status),
+    Fsync(u64, Status),
+    Release(u64, Status),
+
+    MkNod(u64, Status, FileAttr),
+    MkDir(u64, Status, FileAttr),
+    Unlink(u64, Status),
+    Rmdir(u64, Status),
+    Rename(u64, Status),
+
+    /// Target of the symbolic link
+    ReadLink(u64, Status, String),
+    Symlink(u64, Status, FileAttr),
+    Link(u64, Status, FileAttr),
+
+    GetXattr(u64, Status, Xattr),
+    SetXattr(u64, Status),
+    /// Names of the attributes, each terminated by a NUL byte
+    ListXattr(u64, Status, Xattr),
+    RemoveXattr(u64, Status),
+
+    StatFs(u64, Status, FsStats),
+
+    KeepAlive(u64, Status),
 }
 
 impl MofosResponse {
@@ -119,8 +611,12 @@ impl MofosResponse {
         MofosResponse::GetAttr(id, Status::Ok, attrs)
     }
 
-    pub fn new_open(id: u64, status: Status) -> MofosResponse {
-        MofosResponse::Open(id, status)
+    pub fn new_lookup(id: u64, status: Status, attrs: FileAttr) -> MofosResponse {
+        MofosResponse::Lookup(id, status, attrs)
+    }
+
+    pub fn new_open(id: u64, status: Status, fh: u64) -> MofosResponse {
+        MofosResponse::Open(id, status, fh)
     }
 
     pub fn new_readdir(id: u64, status: Status, entries: Vec<Entry>) -> MofosResponse {
@@ -130,71 +626,324 @@ impl MofosResponse {
     pub fn new_read(id: u64, status: Status, data: Vec<u8>) -> MofosResponse {
         MofosResponse::Read(id, status, data)
     }
+
+    /// Identifier of the request this is a response to
+    pub fn id(&self) -> u64 {
+        match *self {
+            MofosResponse::Hello(id, ..)
+            | MofosResponse::GetAttr(id, ..)
+            | MofosResponse::SetAttr(id, ..)
+            | MofosResponse::Lookup(id, ..)
+            | MofosResponse::Open(id, ..)
+            | MofosResponse::Create(id, ..)
+            | MofosResponse::OpenDir(id, ..)
+            | MofosResponse::Read(id, ..)
+            | MofosResponse::Write(id, ..)
+            | MofosResponse::Readdir(id, ..)
+            | MofosResponse::ReleaseDir(id, ..)
+            | MofosResponse::Flush(id, ..)
+            | MofosResponse::Fsync(id, ..)
+            | MofosResponse::Release(id, ..)
+            | MofosResponse::MkNod(id, ..)
+            | MofosResponse::MkDir(id, ..)
+            | MofosResponse::Unlink(id, ..)
+            | MofosResponse::Rmdir(id, ..)
+            | MofosResponse::Rename(id, ..)
+            | MofosResponse::ReadLink(id, ..)
+            | MofosResponse::Symlink(id, ..)
+            | MofosResponse::Link(id, ..)
+            | MofosResponse::GetXattr(id, ..)
+            | MofosResponse::SetXattr(id, ..)
+            | MofosResponse::ListXattr(id, ..)
+            | MofosResponse::RemoveXattr(id, ..)
+            | MofosResponse::StatFs(id, ..)
+            | MofosResponse::KeepAlive(id, ..) => id,
+        }
+    }
 }
 
 impl<'a> TryFrom<&'a [u8]> for MofosResponse {
-    type Error = Box<ErrorKind>;
+    type Error = io::Error;
 
     fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
-        deserialize(data)
+        let envelope = Envelope::decode(data, KIND_RESPONSE)?;
+
+        let resp: MofosResponse = deserialize(envelope.payload).map_err(invalid)?;
+
+        if resp.id() != envelope.id {
+            return Err(invalid("response id does not match its envelope"));
+        }
+
+        Ok(resp)
     }
 }
 
-impl Into<Vec<u8>> for MofosResponse {
-    fn into(self) -> Vec<u8> {
-        serialize(&self).expect("tried to serialize invalid response")
+impl From<MofosResponse> for Vec<u8> {
+    fn from(resp: MofosResponse) -> Vec<u8> {
+        let payload = serialize(&resp).expect("tried to serialize invalid response");
+
+        Envelope::encode(resp.id(), KIND_RESPONSE, &payload)
+    }
+}
+
+/// Extended attribute value or list of names, or only its size when the
+/// request asked for it with a size of 0
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Xattr {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+impl Default for Xattr {
+    fn default() -> Self {
+        Xattr::Size(0)
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
 pub struct FileAttr {
     ino: u64,
+    /// Device the file is on, along with `ino` it identifies the file
+    dev: u64,
     tpe: Type,
     size: u64,
+    /// Number of 512 bytes blocks allocated
+    blocks: u64,
+    /// Preferred size for I/O
+    blksize: u32,
+    atime: Timestamp,
+    mtime: Timestamp,
+    ctime: Timestamp,
+    nlink: u32,
     uid: u32,
     gid: u32,
-    mode: u16,
-}
-
-impl<'a> From<&'a DirEntry> for FileAttr {
-    fn from(entry: &'a DirEntry) -> Self {
-        let md = entry.metadata().expect("failed to read metadata");
-
-        FileAttr::from(&md)
-    }
+    /// Device number of character and block devices
+    rdev: u64,
+    mode: u32,
 }
 
 impl<'a> From<&'a Metadata> for FileAttr {
     fn from(entry: &'a Metadata) -> Self {
         FileAttr {
             ino: entry.ino(),
+            dev: entry.dev(),
             tpe: Type::from(entry.file_type()),
             size: entry.size(),
+            blocks: entry.blocks(),
+            blksize: entry.blksize() as u32,
+            atime: Timestamp::new(entry.atime(), entry.atime_nsec()),
+            mtime: Timestamp::new(entry.mtime(), entry.mtime_nsec()),
+            ctime: Timestamp::new(entry.ctime(), entry.ctime_nsec()),
+            nlink: entry.nlink() as u32,
             uid: entry.uid(),
             gid: entry.gid(),
-            mode: entry.mode() as u16,
+            rdev: entry.rdev(),
+            mode: entry.mode(),
+        }
+    }
+}
+
+impl FileAttr {
+    /// Inode number on the server
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    pub fn kind(&self) -> Type {
+        self.tpe.clone()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.tpe == Type::Dir
+    }
+
+    /// Identity of the file on the server, shared by all its hard links.
+    /// `None` for directories, which only ever have one name.
+    pub fn file_id(&self) -> Option<(u64, u64)> {
+        if self.is_dir() {
+            None
+        } else {
+            Some((self.dev, self.ino))
+        }
+    }
+
+    /// Permission bits of the mode
+    pub fn perm(&self) -> u16 {
+        (self.mode & 0o7777) as u16
+    }
+}
+
+#[cfg(feature = "client")]
+impl FileAttr {
+    /// Attributes reported to the kernel for the local inode `ino`
+    pub fn to_fuse(&self, ino: u64) -> fuse::FileAttr {
+        fuse::FileAttr {
+            ino,
+            size: self.size,
+            blocks: self.blocks,
+            atime: self.atime.into(),
+            mtime: self.mtime.into(),
+            ctime: self.ctime.into(),
+            // linux has no creation time, only macOS uses it
+            crtime: self.ctime.into(),
+            kind: self.kind().into(),
+            perm: self.perm(),
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev as u32,
+            flags: 0,
+        }
+    }
+}
+
+/// Capacity of a filesystem as reported by statvfs(3), counts of blocks are
+/// in units of `frsize`
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct FsStats {
+    blocks: u64,
+    bfree: u64,
+    /// Free blocks available to unprivileged users
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+}
+
+impl<'a> From<&'a libc::statvfs> for FsStats {
+    // the widths of the counts depend on the platform
+    #[allow(clippy::unnecessary_cast)]
+    fn from(st: &'a libc::statvfs) -> Self {
+        FsStats {
+            blocks: st.f_blocks as u64,
+            bfree: st.f_bfree as u64,
+            bavail: st.f_bavail as u64,
+            files: st.f_files as u64,
+            ffree: st.f_ffree as u64,
+            bsize: st.f_bsize as u32,
+            namelen: st.f_namemax as u32,
+            frsize: st.f_frsize as u32,
+        }
+    }
+}
+
+impl FsStats {
+    pub fn bavail(&self) -> u64 {
+        self.bavail
+    }
+}
+
+#[cfg(feature = "client")]
+impl FsStats {
+    pub fn reply(&self, reply: fuse::ReplyStatfs) {
+        reply.statfs(
+            self.blocks,
+            self.bfree,
+            self.bavail,
+            self.files,
+            self.ffree,
+            self.bsize,
+            self.namelen,
+            self.frsize,
+        );
+    }
+}
+
+/// Point in time as seconds and nanoseconds since the epoch
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub struct Timestamp {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    fn new(sec: i64, nsec: i64) -> Timestamp {
+        Timestamp {
+            sec,
+            nsec: nsec as u32,
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<time::Timespec> for Timestamp {
+    fn from(t: time::Timespec) -> Self {
+        Timestamp {
+            sec: t.sec,
+            nsec: t.nsec as u32,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[cfg(feature = "client")]
+impl From<Timestamp> for time::Timespec {
+    fn from(t: Timestamp) -> Self {
+        time::Timespec::new(t.sec, t.nsec as i32)
+    }
+}
+
+/// Attributes changed by a `SetAttr` request, the others are left untouched
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct SetAttrs {
+    /// Permission bits
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Truncates or extends the file to this size
+    pub size: Option<u64>,
+    pub atime: Option<Timestamp>,
+    pub mtime: Option<Timestamp>,
+}
+
+/// Directory entry as sent by the server, `offset` is where the listing
+/// continues after this entry. Attributes are left to a lookup, like
+/// readdir(3) only the type of the entry is known.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Entry {
     name: String,
-    attrs: FileAttr,
+    offset: i64,
+    kind: Type,
+    /// Inode number on the server
+    ino: u64,
 }
 
-impl<'a> From<&'a DirEntry> for Entry {
-    fn from(v: &'a DirEntry) -> Self {
-        // TODO: handle errors
+impl Entry {
+    pub fn new(name: String, offset: i64, kind: Type, ino: u64) -> Entry {
         Entry {
-            name: String::from(v.path().to_str().unwrap()),
-            attrs: FileAttr::from(&v.metadata().unwrap()),
+            name,
+            offset,
+            kind,
+            ino,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Inode number on the server
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    pub fn kind(&self) -> Type {
+        self.kind.clone()
+    }
+
+    /// Number of bytes this entry takes in a serialized response
+    pub fn size(&self) -> usize {
+        serialized_size(self).map_or(0, |size| size as usize)
+    }
 }
 
 #[repr(u8)]
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
 pub enum Type {
     File = 0,
     Dir = 1,
@@ -204,6 +953,7 @@ pub enum Type {
     CharDev = 5,
     BlockDev = 6,
 
+    #[default]
     Unknown = 0xff,
 }
 
@@ -228,3 +978,131 @@ impl From<FileType> for Type {
         }
     }
 }
+
+#[cfg(feature = "client")]
+impl From<Type> for fuse::FileType {
+    fn from(t: Type) -> Self {
+        match t {
+            Type::Dir => fuse::FileType::Directory,
+            Type::Link => fuse::FileType::Symlink,
+            Type::Socket => fuse::FileType::Socket,
+            Type::Fifo => fuse::FileType::NamedPipe,
+            Type::CharDev => fuse::FileType::CharDevice,
+            Type::BlockDev => fuse::FileType::BlockDevice,
+            Type::File | Type::Unknown => fuse::FileType::RegularFile,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate mktemp;
+
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+
+    use self::mktemp::Temp;
+    use std::convert::{TryFrom, TryInto};
+
+    use super::{
+        libc, FileAttr, Hello, MofosRequest, MofosResponse, Status, Timestamp, Type, ENVELOPE_SIZE,
+        FEATURE_XATTR, MAX_READ,
+    };
+
+    #[test]
+    fn file_attr_from_metadata_test() {
+        let temp = Temp::new_dir().expect("could not create temp dir");
+        let file = temp.to_path_buf().join("file");
+
+        fs::write(&file, vec![0u8; 10_000]).expect("failed to create file");
+        fs::hard_link(&file, temp.to_path_buf().join("link")).expect("failed to link");
+
+        let metadata = fs::metadata(&file).unwrap();
+        let attrs = FileAttr::from(&metadata);
+
+        assert_eq!(attrs.tpe, Type::File);
+        assert_eq!(attrs.size, 10_000);
+        assert_eq!(attrs.nlink, 2);
+        assert_eq!(attrs.mode, metadata.mode());
+        assert_eq!(attrs.blocks, metadata.blocks());
+        assert_eq!(
+            attrs.mtime,
+            Timestamp::new(metadata.mtime(), metadata.mtime_nsec())
+        );
+    }
+
+    #[test]
+    fn status_from_error_test() {
+        let raw = io::Error::from_raw_os_error(libc::ENOTEMPTY);
+        let kind = io::Error::new(io::ErrorKind::InvalidInput, "invalid file name");
+
+        assert_eq!(Status::from(&raw), Status::Err(libc::ENOTEMPTY));
+        assert_eq!(Status::from(&raw).errno(), libc::ENOTEMPTY);
+        assert_eq!(Status::from(&kind).errno(), libc::EINVAL);
+        assert_eq!(Status::Err(0).errno(), libc::EIO);
+        assert_eq!(Status::Ok.errno(), 0);
+    }
+
+    #[test]
+    fn hello_negotiate_test() {
+        let client = Hello {
+            version: 1,
+            features: FEATURE_XATTR | 1 << 8,
+            max_message: 16 << 20,
+            session: 1,
+        };
+        let server = Hello {
+            version: 1,
+            features: 1 << 8,
+            max_message: 64 << 10,
+            session: 0,
+        };
+        let session = client.negotiate(&server);
+
+        assert!(!session.has(FEATURE_XATTR));
+        assert!(session.has(1 << 8));
+        assert_eq!(session.max_message, 64 << 10);
+        assert_eq!(session.max_read(), 60 << 10);
+        assert_eq!(client.max_read(), MAX_READ);
+    }
+
+    #[test]
+    fn envelope_test() {
+        let req = MofosRequest::ReadLink {
+            id: 7,
+            path: "link".to_string(),
+        };
+        let bytes: Vec<u8> = req.try_into().expect("failed to serialize");
+
+        assert_eq!(&bytes[0..4], b"MOFS");
+        assert!(matches!(
+            MofosRequest::try_from(bytes.as_slice()),
+            Ok(MofosRequest::ReadLink { id: 7, ref path }) if path == "link"
+        ));
+
+        // a response is never mistaken for a request nor the reverse
+        let resp: Vec<u8> = MofosResponse::ReadLink(7, Status::Ok, String::new()).into();
+
+        assert!(MofosRequest::try_from(resp.as_slice()).is_err());
+        assert!(MofosResponse::try_from(bytes.as_slice()).is_err());
+        assert!(matches!(
+            MofosResponse::try_from(resp.as_slice()),
+            Ok(MofosResponse::ReadLink(7, Status::Ok, _))
+        ));
+
+        // foreign, truncated and corrupt messages are rejected
+        let mut foreign = bytes.clone();
+        foreign[0] = b'X';
+        assert!(MofosRequest::try_from(foreign.as_slice()).is_err());
+
+        assert!(MofosRequest::try_from(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MofosRequest::try_from(&bytes[..ENVELOPE_SIZE - 1]).is_err());
+
+        for at in 4..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[at] ^= 0x10;
+            assert!(MofosRequest::try_from(corrupt.as_slice()).is_err());
+        }
+    }
+}
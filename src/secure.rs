@@ -1 +1,769 @@
+extern crate crypto;
 
+#[cfg(any(not(feature = "client"), test))]
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+#[cfg(any(not(feature = "client"), test))]
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use self::crypto::aead::{AeadDecryptor, AeadEncryptor};
+use self::crypto::chacha20poly1305::ChaCha20Poly1305;
+use self::crypto::hkdf::{hkdf_expand, hkdf_extract};
+use self::crypto::sha2::Sha256;
+
+/// Size of the pre-shared key
+pub const KEY_SIZE: usize = 32;
+
+/// Size of the random numbers each side picks for a session, the one of the
+/// server identifies the session
+const NONCE_SIZE: usize = 16;
+
+const SEQ_SIZE: usize = 8;
+
+const TAG_SIZE: usize = 16;
+
+/// Size of the header of the messages of a session
+const HEADER_SIZE: usize = 1 + NONCE_SIZE + SEQ_SIZE;
+
+/// Bytes added to every sealed packet
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+
+/// Number of sessions the server keeps keys and replay windows for
+#[cfg(any(not(feature = "client"), test))]
+const MAX_SESSIONS: usize = 256;
+
+/// Number of those sessions opened from a single host, a host replaying
+/// hellos then only ever evicts its own sessions
+#[cfg(any(not(feature = "client"), test))]
+const MAX_HOST_SESSIONS: usize = MAX_SESSIONS / 16;
+
+/// First byte of every packet, telling what it is
+const DATA: u8 = 0;
+/// Client opening a session, carries its nonce
+const HELLO: u8 = 1;
+/// Server answering a hello with its own nonce, which identifies the session
+const CHALLENGE: u8 = 2;
+/// Server telling a client it does not know the session it used, carries its
+/// id
+const RESET: u8 = 3;
+
+const HELLO_INFO: &[u8] = b"mofos hello";
+const CHALLENGE_INFO: &[u8] = b"mofos challenge";
+const RESET_INFO: &[u8] = b"mofos reset";
+const CLIENT_INFO: &[u8] = b"mofos client to server";
+const SERVER_INFO: &[u8] = b"mofos server to client";
+
+type Nonce = [u8; NONCE_SIZE];
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn random_nonce() -> Result<Nonce, Error> {
+    let mut nonce = [0u8; NONCE_SIZE];
+
+    random_bytes(&mut nonce)?;
+
+    Ok(nonce)
+}
+
+/// Fills `buf` with random bytes from the kernel
+pub fn random_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// Secret shared by the client and the server
+#[derive(Clone)]
+pub struct Key([u8; KEY_SIZE]);
+
+impl Key {
+    #[cfg(any(feature = "client", test))]
+    pub fn generate() -> Result<Key, Error> {
+        let mut key = [0u8; KEY_SIZE];
+
+        random_bytes(&mut key)?;
+
+        Ok(Key(key))
+    }
+
+    /// Parses a key written as hexadecimal digits
+    pub fn from_hex(hex: &str) -> Result<Key, Error> {
+        let hex = hex.trim();
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid key");
+
+        if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut key = [0u8; KEY_SIZE];
+
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Key(key))
+    }
+
+    #[cfg(any(feature = "client", test))]
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Reads a key stored as hexadecimal digits in the file at `path`
+    pub fn load(path: &Path) -> Result<Key, Error> {
+        Key::from_hex(&fs::read_to_string(path)?)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+/// Derives the key used for `info` in the session whose nonces are `salt`,
+/// each direction uses its own key so that both sides may count their
+/// sequence numbers from zero without ever reusing a nonce
+fn derive_key(key: &Key, salt: &[u8], info: &[u8]) -> [u8; KEY_SIZE] {
+    let mut prk = [0u8; KEY_SIZE];
+    let mut okm = [0u8; KEY_SIZE];
+
+    hkdf_extract(Sha256::new(), salt, &key.0, &mut prk);
+    hkdf_expand(Sha256::new(), &prk, info, &mut okm);
+
+    okm
+}
+
+/// Tag authenticating `header` with `key`, which is never used for anything
+/// else so the cipher nonce may stay zero
+fn tag(key: &[u8; KEY_SIZE], header: &[u8]) -> [u8; TAG_SIZE] {
+    let mut tag = [0u8; TAG_SIZE];
+
+    ChaCha20Poly1305::new(key, &[0u8; SEQ_SIZE], header).encrypt(&[], &mut [], &mut tag);
+
+    tag
+}
+
+fn verify(key: &[u8; KEY_SIZE], packet: &[u8]) -> Result<(), Error> {
+    let (header, tag) = packet.split_at(packet.len() - TAG_SIZE);
+
+    if !ChaCha20Poly1305::new(key, &[0u8; SEQ_SIZE], header).decrypt(&[], &mut [], tag) {
+        return Err(invalid("forged packet"));
+    }
+
+    Ok(())
+}
+
+/// Header followed by its tag
+fn tagged(key: &[u8; KEY_SIZE], header: Vec<u8>) -> Vec<u8> {
+    let tag = tag(key, &header);
+    let mut packet = header;
+
+    packet.extend_from_slice(&tag);
+
+    packet
+}
+
+/// Packet sent by a client opening a session with `nonce`
+#[cfg(any(feature = "client", test))]
+fn hello(key: &Key, nonce: &Nonce) -> Vec<u8> {
+    let mut header = vec![HELLO];
+
+    header.extend_from_slice(nonce);
+
+    tagged(&derive_key(key, nonce, HELLO_INFO), header)
+}
+
+/// Packet answering the hello of `client`, `server` identifies the session
+#[cfg(any(not(feature = "client"), test))]
+fn challenge(key: &Key, client: &Nonce, server: &Nonce) -> Vec<u8> {
+    let mut header = vec![CHALLENGE];
+
+    header.extend_from_slice(client);
+    header.extend_from_slice(server);
+
+    tagged(&derive_key(key, &header[1..], CHALLENGE_INFO), header)
+}
+
+/// Packet telling the client the session `id` is unknown. The server no
+/// longer has the keys of the session, only the pre-shared one proves the
+/// packet comes from a server.
+#[cfg(any(not(feature = "client"), test))]
+fn reset(key: &Key, id: &Nonce) -> Vec<u8> {
+    let mut header = vec![RESET];
+
+    header.extend_from_slice(id);
+
+    tagged(&derive_key(key, id, RESET_INFO), header)
+}
+
+/// Splits `packet` of `kind` made of a header of `size` and a tag
+fn split_tagged(packet: &[u8], kind: u8, size: usize) -> Result<&[u8], Error> {
+    if packet.len() != size + TAG_SIZE || packet[0] != kind {
+        return Err(invalid("malformed handshake"));
+    }
+
+    Ok(&packet[1..size])
+}
+
+/// Remembers which of the last 64 sequence numbers were seen to reject
+/// replayed packets while tolerating reordering
+#[derive(Default)]
+struct ReplayWindow {
+    /// Highest sequence number accepted plus one, zero if none was
+    next: u64,
+    /// Bit `i` is set when `next - 1 - i` was accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accepts(&self, seq: u64) -> bool {
+        if seq >= self.next {
+            return true;
+        }
+
+        let age = self.next - 1 - seq;
+
+        age < 64 && self.seen & (1 << age) == 0
+    }
+
+    fn update(&mut self, seq: u64) {
+        if seq >= self.next {
+            let shift = seq - self.next + 1;
+
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = seq + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - seq);
+        }
+    }
+}
+
+/// State of one direction pair of a session
+struct Session {
+    id: Nonce,
+    send_key: [u8; KEY_SIZE],
+    recv_key: [u8; KEY_SIZE],
+    send_seq: u64,
+    window: ReplayWindow,
+}
+
+impl Session {
+    /// Session opened by the client with `client` and answered by the server
+    /// with `server`, both sides contribute to its keys so that neither
+    /// accepts the packets of an earlier session
+    fn new(key: &Key, client: &Nonce, server: &Nonce, is_client: bool) -> Session {
+        let (send_info, recv_info) = if is_client {
+            (CLIENT_INFO, SERVER_INFO)
+        } else {
+            (SERVER_INFO, CLIENT_INFO)
+        };
+        let mut salt = [0u8; 2 * NONCE_SIZE];
+
+        salt[..NONCE_SIZE].copy_from_slice(client);
+        salt[NONCE_SIZE..].copy_from_slice(server);
+
+        Session {
+            id: *server,
+            send_key: derive_key(key, &salt, send_info),
+            recv_key: derive_key(key, &salt, recv_info),
+            send_seq: 0,
+            window: ReplayWindow::default(),
+        }
+    }
+
+    fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let seq = self.send_seq;
+
+        self.send_seq += 1;
+
+        let mut packet = vec![0u8; OVERHEAD + plain.len()];
+        let (header, body) = packet.split_at_mut(HEADER_SIZE);
+        let (cipher, tag) = body.split_at_mut(plain.len());
+
+        header[0] = DATA;
+        header[1..1 + NONCE_SIZE].copy_from_slice(&self.id);
+        header[1 + NONCE_SIZE..].copy_from_slice(&seq.to_be_bytes());
+
+        ChaCha20Poly1305::new(&self.send_key, &seq.to_be_bytes(), header)
+            .encrypt(plain, cipher, tag);
+
+        packet
+    }
+
+    /// Authenticates and decrypts a packet of this session, rejecting
+    /// forged or replayed ones
+    fn open(&mut self, packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let (seq, plain) = self.decrypt(packet)?;
+
+        self.window.update(seq);
+
+        Ok(plain)
+    }
+
+    fn decrypt(&self, packet: &[u8]) -> Result<(u64, Vec<u8>), Error> {
+        let (header, body) = split_packet(packet)?;
+        let mut seq = [0u8; SEQ_SIZE];
+
+        seq.copy_from_slice(&header[1 + NONCE_SIZE..]);
+
+        if !self.window.accepts(u64::from_be_bytes(seq)) {
+            return Err(invalid("replayed packet"));
+        }
+
+        let (cipher, tag) = body.split_at(body.len() - TAG_SIZE);
+        let mut plain = vec![0u8; cipher.len()];
+
+        if !ChaCha20Poly1305::new(&self.recv_key, &seq, header).decrypt(cipher, &mut plain, tag) {
+            return Err(invalid("forged packet"));
+        }
+
+        Ok((u64::from_be_bytes(seq), plain))
+    }
+}
+
+/// Splits a packet of a session into its header and the rest
+fn split_packet(packet: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if packet.len() < OVERHEAD || packet[0] != DATA {
+        return Err(invalid("packet too short"));
+    }
+
+    Ok(packet.split_at(HEADER_SIZE))
+}
+
+/// Session a packet of `kind` refers to
+fn session_id(packet: &[u8], kind: u8) -> Result<Nonce, Error> {
+    if packet.len() < 1 + NONCE_SIZE || packet[0] != kind {
+        return Err(invalid("packet too short"));
+    }
+
+    let mut id = [0u8; NONCE_SIZE];
+
+    id.copy_from_slice(&packet[1..1 + NONCE_SIZE]);
+
+    Ok(id)
+}
+
+/// Client side of the secure channel. A session is opened with a handshake
+/// before any message is sealed and opened again whenever the server lost it.
+#[cfg(any(feature = "client", test))]
+pub struct ClientChannel {
+    key: Key,
+    /// Picked for every handshake so that the answer of the server can not be
+    /// replayed to us
+    nonce: Nonce,
+    session: Option<Session>,
+}
+
+#[cfg(any(feature = "client", test))]
+impl ClientChannel {
+    pub fn new(key: &Key) -> Result<ClientChannel, Error> {
+        Ok(ClientChannel {
+            key: key.clone(),
+            nonce: random_nonce()?,
+            session: None,
+        })
+    }
+
+    /// Whether a session is open, messages can only be sealed once it is
+    pub fn is_open(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Starts a handshake, returns the packet to send to the server until it
+    /// answers with a challenge
+    pub fn hello(&mut self) -> Result<Vec<u8>, Error> {
+        self.nonce = random_nonce()?;
+        self.session = None;
+
+        Ok(hello(&self.key, &self.nonce))
+    }
+
+    /// Completes the handshake with the challenge the server answered
+    pub fn accept(&mut self, packet: &[u8]) -> Result<(), Error> {
+        let nonces = split_tagged(packet, CHALLENGE, 1 + 2 * NONCE_SIZE)?;
+        let (client, server) = nonces.split_at(NONCE_SIZE);
+
+        if client != self.nonce {
+            return Err(invalid("challenge to another hello"));
+        }
+
+        verify(&derive_key(&self.key, nonces, CHALLENGE_INFO), packet)?;
+
+        let mut id = [0u8; NONCE_SIZE];
+
+        id.copy_from_slice(server);
+        self.session = Some(Session::new(&self.key, &self.nonce, &id, true));
+
+        Ok(())
+    }
+
+    pub fn seal(&mut self, plain: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.session {
+            Some(ref mut session) => Ok(session.seal(plain)),
+            None => Err(Error::new(ErrorKind::NotConnected, "no session open")),
+        }
+    }
+
+    /// Authenticates and decrypts a packet of the session, fails with
+    /// `ConnectionReset` when the server no longer knows the session
+    pub fn open(&mut self, packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let session = self
+            .session
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "no session open"))?;
+
+        if packet.first() == Some(&RESET) {
+            if split_tagged(packet, RESET, 1 + NONCE_SIZE)? != session.id {
+                return Err(invalid("foreign session"));
+            }
+
+            verify(&derive_key(&self.key, &session.id, RESET_INFO), packet)?;
+            self.session = None;
+
+            return Err(Error::new(
+                ErrorKind::ConnectionReset,
+                "server lost the session",
+            ));
+        }
+
+        if session_id(packet, DATA)? != session.id {
+            return Err(invalid("foreign session"));
+        }
+
+        session.open(packet)
+    }
+}
+
+/// What a packet received by the server turned out to be
+#[cfg(any(not(feature = "client"), test))]
+#[derive(PartialEq, Debug)]
+pub enum Received {
+    /// Message of an open session
+    Message(Vec<u8>),
+    /// Part of a handshake, the packet to send back to the peer as is
+    Reply(Vec<u8>),
+}
+
+/// Server side of the secure channel, keeps track of the session each peer
+/// uses so that responses are sealed for it. Sessions are only created by a
+/// handshake, the packets of a session that was forgotten are never
+/// accepted again.
+#[cfg(any(not(feature = "client"), test))]
+pub struct ServerChannel {
+    key: Key,
+    sessions: HashMap<Nonce, Session>,
+    /// Sessions from the oldest, along with the host that opened them
+    order: VecDeque<(Nonce, IpAddr)>,
+    peers: HashMap<SocketAddr, Nonce>,
+}
+
+#[cfg(any(not(feature = "client"), test))]
+impl ServerChannel {
+    pub fn new(key: Key) -> ServerChannel {
+        ServerChannel {
+            key,
+            sessions: HashMap::new(),
+            order: VecDeque::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Handles a packet received from `from`, returns the message it carried
+    /// or what to answer
+    pub fn open(&mut self, from: SocketAddr, packet: &[u8]) -> Result<Received, Error> {
+        match packet.first() {
+            Some(&HELLO) => self.answer(from, packet).map(Received::Reply),
+
+            Some(&DATA) => {
+                let id = session_id(packet, DATA)?;
+                let session = match self.sessions.get_mut(&id) {
+                    Some(session) => session,
+
+                    // tells the client to open a new session
+                    None => return Ok(Received::Reply(reset(&self.key, &id))),
+                };
+                let plain = session.open(packet)?;
+
+                self.peers.insert(from, id);
+
+                Ok(Received::Message(plain))
+            }
+
+            _ => Err(invalid("unexpected packet")),
+        }
+    }
+
+    /// Opens a session for the hello `packet` received from `from`, returns
+    /// the challenge answering it
+    fn answer(&mut self, from: SocketAddr, packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = split_tagged(packet, HELLO, 1 + NONCE_SIZE)?;
+        let mut client = [0u8; NONCE_SIZE];
+
+        client.copy_from_slice(nonce);
+        verify(&derive_key(&self.key, &client, HELLO_INFO), packet)?;
+
+        // a fresh nonce for every hello, even a replayed one, makes for keys
+        // no earlier session used
+        let server = random_nonce()?;
+
+        self.insert(from.ip(), Session::new(&self.key, &client, &server, false));
+
+        Ok(challenge(&self.key, &client, &server))
+    }
+
+    /// Encrypts `plain` for the session `to` last used
+    pub fn seal(&mut self, to: SocketAddr, plain: &[u8]) -> Result<Vec<u8>, Error> {
+        let sessions = &mut self.sessions;
+        let session = self
+            .peers
+            .get(&to)
+            .and_then(|id| sessions.get_mut(id))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no session for peer"))?;
+
+        Ok(session.seal(plain))
+    }
+
+    /// Keeps `session` opened from `host`, forgetting the oldest session of
+    /// that host first if it has too many and then the oldest of all
+    fn insert(&mut self, host: IpAddr, session: Session) {
+        let opened = self.order.iter().filter(|(_, from)| *from == host).count();
+
+        if opened >= MAX_HOST_SESSIONS {
+            let oldest = self.order.iter().position(|(_, from)| *from == host);
+
+            if let Some((id, _)) = oldest.and_then(|position| self.order.remove(position)) {
+                self.remove(&id);
+            }
+        }
+
+        self.order.push_back((session.id, host));
+        self.sessions.insert(session.id, session);
+
+        while self.order.len() > MAX_SESSIONS {
+            if let Some((id, _)) = self.order.pop_front() {
+                self.remove(&id);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: &Nonce) {
+        self.sessions.remove(id);
+        self.peers.retain(|_, session| session != id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+    use super::{
+        reset, ClientChannel, Key, Received, ReplayWindow, ServerChannel, MAX_SESSIONS, OVERHEAD,
+        RESET,
+    };
+
+    const PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000));
+    const OTHER_PEER: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), 4000));
+
+    fn key() -> Key {
+        Key::from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+            .expect("invalid key")
+    }
+
+    /// Client with a session open with `server`
+    fn connect(server: &mut ServerChannel) -> ClientChannel {
+        let mut client = ClientChannel::new(&key()).expect("no randomness");
+        let hello = client.hello().expect("no randomness");
+
+        match server.open(PEER, &hello) {
+            Ok(Received::Reply(challenge)) => client.accept(&challenge).expect("bad challenge"),
+            _ => panic!("hello not answered"),
+        }
+
+        client
+    }
+
+    #[test]
+    fn key_hex_test() {
+        let key = Key::generate().expect("no randomness");
+
+        assert_eq!(Key::from_hex(&key.to_hex()).expect("invalid key").0, key.0);
+        assert!(Key::from_hex("00").is_err());
+        assert!(Key::from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = connect(&mut server);
+
+        let req = client.seal(b"request").expect("seal failed");
+
+        assert_eq!(req.len(), OVERHEAD + 7);
+        assert_eq!(
+            server.open(PEER, &req).expect("open failed"),
+            Received::Message(b"request".to_vec())
+        );
+
+        let resp = server.seal(PEER, b"response").expect("seal failed");
+
+        assert_eq!(client.open(&resp).expect("open failed"), b"response");
+    }
+
+    #[test]
+    fn no_session_before_handshake_test() {
+        let mut client = ClientChannel::new(&key()).expect("no randomness");
+
+        assert!(!client.is_open());
+        assert!(client.seal(b"request").is_err());
+    }
+
+    #[test]
+    fn replay_rejected_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = connect(&mut server);
+        let req = client.seal(b"request").expect("seal failed");
+
+        assert!(server.open(PEER, &req).is_ok());
+        assert!(server.open(PEER, &req).is_err());
+    }
+
+    #[test]
+    fn replay_after_eviction_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = connect(&mut server);
+        let req = client.seal(b"request").expect("seal failed");
+
+        assert!(server.open(PEER, &req).is_ok());
+
+        // enough new sessions for the server to forget the first one
+        for _ in 0..MAX_SESSIONS {
+            connect(&mut server);
+        }
+
+        assert!(matches!(server.open(PEER, &req), Ok(Received::Reply(_))));
+
+        // nor is it accepted by a restarted server
+        let mut restarted = ServerChannel::new(key());
+
+        assert!(matches!(restarted.open(PEER, &req), Ok(Received::Reply(_))));
+    }
+
+    #[test]
+    fn lost_session_reset_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = connect(&mut server);
+        let mut restarted = ServerChannel::new(key());
+
+        let reset = match restarted.open(PEER, &client.seal(b"request").unwrap()) {
+            Ok(Received::Reply(reset)) => reset,
+            _ => panic!("unknown session accepted"),
+        };
+
+        assert!(client.open(&reset).is_err());
+        assert!(!client.is_open());
+    }
+
+    #[test]
+    fn forged_reset_rejected_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = connect(&mut server);
+        let id = client.session.as_ref().unwrap().id;
+        let mut forged = vec![RESET];
+
+        // knowing the session id is not enough to end the session
+        forged.extend_from_slice(&id);
+        assert!(client.open(&forged).is_err());
+
+        let other = Key::generate().expect("no randomness");
+
+        assert!(client.open(&reset(&other, &id)).is_err());
+        assert!(client.is_open());
+
+        let req = client.seal(b"request").expect("seal failed");
+
+        assert!(matches!(server.open(PEER, &req), Ok(Received::Message(_))));
+    }
+
+    #[test]
+    fn replayed_hello_does_not_evict_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = ClientChannel::new(&key()).expect("no randomness");
+        let hello = client.hello().expect("no randomness");
+
+        match server.open(PEER, &hello) {
+            Ok(Received::Reply(challenge)) => client.accept(&challenge).expect("bad challenge"),
+            _ => panic!("hello not answered"),
+        }
+
+        // replays only evict the sessions of the host they come from
+        for _ in 0..MAX_SESSIONS {
+            assert!(matches!(
+                server.open(OTHER_PEER, &hello),
+                Ok(Received::Reply(_))
+            ));
+        }
+
+        let req = client.seal(b"request").expect("seal failed");
+
+        assert!(matches!(server.open(PEER, &req), Ok(Received::Message(_))));
+    }
+
+    #[test]
+    fn replayed_challenge_rejected_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = ClientChannel::new(&key()).expect("no randomness");
+        let challenge = match server.open(PEER, &client.hello().unwrap()) {
+            Ok(Received::Reply(challenge)) => challenge,
+            _ => panic!("hello not answered"),
+        };
+
+        // the answer to an earlier hello does not open a session
+        client.hello().unwrap();
+        assert!(client.accept(&challenge).is_err());
+        assert!(!client.is_open());
+    }
+
+    #[test]
+    fn tampering_rejected_test() {
+        let mut server = ServerChannel::new(key());
+        let mut client = connect(&mut server);
+        let mut req = client.seal(b"request").expect("seal failed");
+        let last = req.len() - 1;
+
+        req[last] ^= 1;
+
+        assert!(server.open(PEER, &req).is_err());
+        assert!(server.open(PEER, b"short").is_err());
+    }
+
+    #[test]
+    fn wrong_key_rejected_test() {
+        let mut client = ClientChannel::new(&Key::generate().unwrap()).expect("no randomness");
+        let mut server = ServerChannel::new(key());
+
+        assert!(server.open(PEER, &client.hello().unwrap()).is_err());
+        // nothing may be sent back to an unauthenticated peer
+        assert!(server.seal(PEER, b"response").is_err());
+    }
+
+    #[test]
+    fn replay_window_test() {
+        let mut window = ReplayWindow::default();
+
+        for seq in &[0, 2, 1, 70] {
+            assert!(window.accepts(*seq));
+            window.update(*seq);
+            assert!(!window.accepts(*seq));
+        }
+
+        // too old to be told apart from a replay
+        assert!(!window.accepts(3));
+        assert!(window.accepts(69));
+    }
+}
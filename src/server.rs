@@ -1,211 +1,2127 @@
-use std::collections::HashMap;
+extern crate libc;
+
+use std::collections::{HashMap, VecDeque};
 use std::convert::{Into, TryFrom};
+use std::ffi::CString;
 use std::fs;
-use std::io::{Error, ErrorKind, SeekFrom};
-use std::net::{SocketAddr, UdpSocket};
-use std::os::unix::fs::FileExt;
-use std::path::Path;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{self as unix_fs, DirBuilderExt, DirEntryExt, FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use super::jail::Jail;
 use super::proto::*;
+use super::transport::{Listener, Peer, MAX_MESSAGE};
+
+mod xattr;
+
+/// Largest size of the entries returned by a single readdir, about what the
+/// kernel asks for at once
+const READDIR_SIZE: usize = 4 << 10;
+
+/// Number of responses kept for replay to retransmitted requests
+const REPLY_CACHE_SIZE: usize = 1024;
 
-const MTU: u64 = 1500;
+/// Time after which a client of a connectionless transport that sent no
+/// request is assumed gone, closing the files it left open
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 
-enum ServerStatus {
-    ShouldExit,
-    ShouldContinue,
-    BadRequest,
-    IOError,
+/// Duplicate request cache remembering the responses to recent non-idempotent
+/// requests so that a retransmission is answered without running the
+/// operation a second time. The oldest entries are evicted first.
+struct ReplyCache {
+    capacity: usize,
+    responses: HashMap<(Peer, u64), MofosResponse>,
+    order: VecDeque<(Peer, u64)>,
 }
 
-pub struct MofosServer {
-    socket: UdpSocket,
-    pending: HashMap<u64, MofosResponse>,
-    files: HashMap<String, fs::File>,
-    destination: String,
+impl ReplyCache {
+    fn new(capacity: usize) -> ReplyCache {
+        ReplyCache {
+            capacity,
+            responses: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn get(&self, peer: Peer, id: u64) -> Option<&MofosResponse> {
+        self.responses.get(&(peer, id))
+    }
+
+    /// Drops the responses to `peer`, whose next requests may reuse their ids
+    fn forget(&mut self, peer: Peer) {
+        self.order.retain(|&(p, _)| p != peer);
+        self.responses.retain(|&(p, _), _| p != peer);
+    }
+
+    fn insert(&mut self, peer: Peer, id: u64, resp: MofosResponse) {
+        if self.responses.insert((peer, id), resp).is_some() {
+            return;
+        }
+
+        self.order.push_back((peer, id));
+
+        while self.order.len() > self.capacity {
+            if let Some(key) = self.order.pop_front() {
+                self.responses.remove(&key);
+            }
+        }
+    }
 }
 
-impl MofosServer {
-    pub fn new(addr: SocketAddr, dir: &Path) -> Result<MofosServer, Error> {
-        if !dir.is_dir() {
-            return Err(Error::new(ErrorKind::Other, "is not a directory"));
+/// Options opening a file the way the open(2) `flags` ask for. Only the
+/// access mode goes through `OpenOptions`, whose checks refuse combinations
+/// such as read-only with `O_CREAT` or `O_TRUNC`, every other flag is passed
+/// to open(2) as is.
+fn open_options(flags: u32) -> fs::OpenOptions {
+    let flags = flags as i32;
+    let mut options = fs::OpenOptions::new();
+
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => options.write(true),
+        libc::O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+
+    options.custom_flags(flags & !libc::O_ACCMODE);
+
+    options
+}
+
+/// Path of the entry `name` of the directory `parent` as sent by a client,
+/// names are single path components
+fn entry_path(parent: &str, name: &str) -> Result<String, Error> {
+    if name.is_empty() || name.contains('/') {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid file name"));
+    }
+
+    Ok(child_path(parent, name))
+}
+
+/// Status reported for the outcome of an operation
+fn status_of<T>(result: Result<T, Error>) -> Status {
+    match result {
+        Ok(_) => Status::Ok,
+        Err(e) => Status::from(&e),
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2(from: &Path, to: &Path, flags: u32) -> Result<(), Error> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+
+    if unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn renameat2(_from: &Path, _to: &Path, _flags: u32) -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "rename flags are not supported",
+    ))
+}
+
+/// Capacity of the filesystem holding `path`
+fn statvfs(path: &Path) -> Result<FsStats, Error> {
+    let path = c_path(path)?;
+    let mut st = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    if unsafe { libc::statvfs(path.as_ptr(), st.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(FsStats::from(unsafe { &st.assume_init() }))
+}
+
+/// Sets the permission bits of the file at `path` without following a
+/// symbolic link there
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let path = c_path(path)?;
+    let mode = (mode & 0o7777) as libc::mode_t;
+
+    if unsafe {
+        libc::fchmodat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            mode,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Sets the access and modification times of the file at `path`, the ones
+/// that are `None` are left untouched. A symbolic link there is not followed.
+fn set_times(path: &Path, atime: Option<Timestamp>, mtime: Option<Timestamp>) -> Result<(), Error> {
+    let timespec = |t: Option<Timestamp>| match t {
+        Some(t) => libc::timespec {
+            tv_sec: t.sec as libc::time_t,
+            tv_nsec: t.nsec as libc::c_long,
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    };
+    let times = [timespec(atime), timespec(mtime)];
+    let path = c_path(path)?;
+
+    let flags = libc::AT_SYMLINK_NOFOLLOW;
+
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), flags) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// What this server handles, sent to clients opening a session
+fn server_hello() -> Hello {
+    let features = if cfg!(target_os = "linux") {
+        FEATURE_XATTR
+    } else {
+        0
+    };
+
+    Hello {
+        version: PROTOCOL_VERSION,
+        features,
+        max_message: MAX_MESSAGE as u32,
+        session: 0,
+    }
+}
+
+fn bad_handle() -> Error {
+    Error::from_raw_os_error(libc::EBADF)
+}
+
+/// Reported to peers sending requests without having opened a session
+fn no_session() -> Error {
+    Error::from_raw_os_error(libc::EPROTO)
+}
+
+/// File or directory opened on behalf of a client
+enum Handle {
+    File(fs::File),
+    Dir(Dir),
+}
+
+/// Directory opened by a client. Its listing is read when reading starts and
+/// kept for the following pages, which then only cost their own size.
+struct Dir {
+    path: PathBuf,
+    listing: Vec<Entry>,
+}
+
+impl Dir {
+    fn new(path: PathBuf) -> Dir {
+        Dir {
+            path,
+            listing: Vec::new(),
+        }
+    }
+
+    /// Entries that follow `offset`, as many as fit in a single response. The
+    /// listing is over once no entry is returned.
+    fn read(&mut self, offset: i64) -> Result<Vec<Entry>, Error> {
+        let skip = usize::try_from(offset)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "negative offset"))?;
+
+        // reading from the start again, as after rewinddir(3), shows what
+        // changed since the directory was last read
+        if skip == 0 {
+            self.listing = Dir::list(&self.path)?;
+        }
+
+        let mut entries = Vec::new();
+        let mut size = 0;
+
+        for entry in self.listing.get(skip..).unwrap_or_default() {
+            size += entry.size();
+
+            if size > READDIR_SIZE && !entries.is_empty() {
+                break;
+            }
+
+            entries.push(entry.clone());
+        }
+
+        Ok(entries)
+    }
+
+    /// Every entry of the directory at `path`, offsets are positions in the
+    /// order it is read in
+    fn list(path: &Path) -> Result<Vec<Entry>, Error> {
+        let mut listing = Vec::new();
+
+        for (position, entry) in fs::read_dir(path)?.enumerate() {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // the type comes with the entry on most filesystems, saving a stat
+            let kind = entry.file_type().map_or(Type::Unknown, Type::from);
+
+            listing.push(Entry::new(name, position as i64 + 1, kind, entry.ino()));
+        }
+
+        Ok(listing)
+    }
+}
+
+/// Handles opened by a client, they are only valid for that client and are
+/// all closed once it is gone
+struct Session {
+    /// Number the client picked in its hello
+    nonce: u64,
+    handles: HashMap<u64, Handle>,
+    last_handle: u64,
+    last_seen: Instant,
+}
+
+impl Session {
+    fn new(nonce: u64) -> Session {
+        Session {
+            nonce,
+            handles: HashMap::new(),
+            last_handle: 0,
+            last_seen: Instant::now(),
         }
+    }
+
+    /// Stores `handle`, returns the number the client refers to it by
+    fn insert(&mut self, handle: Handle) -> u64 {
+        self.last_handle += 1;
+        self.handles.insert(self.last_handle, handle);
+
+        self.last_handle
+    }
+}
 
+pub struct MofosServer {
+    listener: Box<dyn Listener>,
+    /// Responses already sent, replayed when a request is retransmitted
+    pending: ReplyCache,
+    /// Open handles of each client
+    sessions: HashMap<Peer, Session>,
+    /// Every path received is resolved inside the exported directory
+    jail: Jail,
+    /// Number of corrupt or foreign messages dropped
+    rejected: u64,
+}
+
+impl MofosServer {
+    pub fn new(listener: Box<dyn Listener>, dir: &Path) -> Result<MofosServer, Error> {
         Ok(MofosServer {
-            socket: UdpSocket::bind(addr)?,
-            files: HashMap::new(),
-            pending: HashMap::new(),
-            destination: String::from(dir.to_str().unwrap()),
+            listener,
+            sessions: HashMap::new(),
+            pending: ReplyCache::new(REPLY_CACHE_SIZE),
+            jail: Jail::new(dir)?,
+            rejected: 0,
         })
     }
 
+    /// Chroots the server into the exported directory
+    pub fn chroot(&mut self) -> Result<(), Error> {
+        info!("chrooting into {}", self.jail.root().display());
+
+        self.jail.chroot()
+    }
+
     pub fn run(&mut self) -> Result<(), Error> {
-        // TODO: chroot server into destination directory
+        info!("serving {}", self.jail.root().display());
 
         self.server_loop()
     }
 
     fn server_loop(&mut self) -> Result<(), Error> {
-        // we should not send more than the MTU so 1500 is ok
-        let buf: &mut [u8] = &mut [0u8; 1500];
-
         loop {
-            match self.socket.recv_from(buf) {
-                Ok((recvd, addr)) => {
-                    match MofosRequest::try_from(&buf[0..recvd]) {
-                        Ok(req) => match self.process_request(&req) {
-                            Ok(resp) => {
-                                let bytes: Vec<u8> = resp.into();
-
-                                match self.socket.send_to(bytes.as_slice(), addr) {
-                                    Ok(sent) => {
-                                        println!("sent {} bytes", sent);
-                                    }
-
-                                    Err(e) => {
-                                        return Err(e);
-                                    }
-                                };
-                            }
-
-                            Err(e) => {
-                                println!("failed to process request: {}", e);
-                            }
-                        },
-
-                        Err(e) => {
-                            println!("invalid request received: {}", e);
-                        }
-                    };
+            let (peer, payload) = match self.listener.recv() {
+                Ok(received) => received,
+
+                // the only client closed the standard streams
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    info!("client disconnected");
+                    return Ok(());
                 }
 
                 Err(e) => {
-                    println!("error reading from socket: {}", e);
+                    error!("error reading from transport: {}", e);
                     return Err(e);
                 }
             };
+
+            self.collect_sessions(Instant::now());
+
+            match MofosRequest::try_from(payload.as_slice()) {
+                Ok(req) => {
+                    if let Some(resp) = self.handle_request(peer, &req) {
+                        if let Err(e) = self.send_response(peer, resp) {
+                            error!("failed to send response to {}: {}", peer, e);
+                        }
+                    }
+                }
+
+                Err(e) => {
+                    self.rejected += 1;
+                    warn!(
+                        "rejected message from {} ({} so far): {}",
+                        peer, self.rejected, e
+                    );
+                }
+            };
         }
     }
 
-    fn process_request(&mut self, req: &MofosRequest) -> Result<MofosResponse, Error> {
-        match req {
-            MofosRequest::GetAttr { id, path } => match fs::metadata(path) {
-                Ok(metadata) => {
-                    let resp = MofosResponse::new_get_attr(*id, FileAttr::from(&metadata));
+    /// Closes the handles of the clients that are gone as of `now`
+    fn collect_sessions(&mut self, now: Instant) {
+        for peer in self.listener.disconnected() {
+            self.pending.forget(peer);
 
-                    Ok(resp)
-                }
+            if self.sessions.remove(&peer).is_some() {
+                info!("{} disconnected, closed its handles", peer);
+            }
+        }
 
-                Err(e) => Err(e),
-            },
+        if self.listener.is_connected() {
+            return;
+        }
 
-            MofosRequest::Open { id, path, flags } => {
-                if let Some(_) = self.files.get(path) {
-                    return Ok(MofosResponse::new_open(*id, Status::Ok));
-                }
+        let pending = &mut self.pending;
 
-                // TODO: handle flags
-                match fs::File::open(path) {
-                    Ok(file) => {
-                        self.files.insert(path.to_string(), file);
-                        Ok(MofosResponse::new_open(*id, Status::Ok))
-                    }
+        self.sessions.retain(|&peer, session| {
+            let alive = now.duration_since(session.last_seen) < SESSION_TIMEOUT;
 
-                    Err(e) => Err(e),
-                }
+            if !alive {
+                info!("{} timed out, closed its handles", peer);
+                pending.forget(peer);
             }
 
-            MofosRequest::Readdir { id, path, offset } => {
-                // FIXME: dynamic entry count
-                let entry_count: usize = 20;
-                let native_entries: Vec<fs::DirEntry> = fs::read_dir(path)?
-                    .take(entry_count)
-                    .map(|e| e.unwrap())
-                    .collect();
-                let entries = native_entries.iter().map(|e| Entry::from(e)).collect();
+            alive
+        });
+    }
+
+    /// Forgets `peer` and closes the handles it left open
+    fn end_session(&mut self, peer: Peer) {
+        self.pending.forget(peer);
+
+        if self.sessions.remove(&peer).is_some() {
+            info!("{} left, closed its handles", peer);
+        }
+    }
+
+    /// Starts the session `nonce` of `peer`. Whatever an earlier session of
+    /// the same peer left behind is dropped, the client starting over with
+    /// the same address must not get the responses meant for it.
+    fn start_session(&mut self, peer: Peer, nonce: u64) {
+        // the hello may have been retransmitted
+        if self.sessions.get(&peer).map(|session| session.nonce) == Some(nonce) {
+            return;
+        }
+
+        if self.sessions.insert(peer, Session::new(nonce)).is_some() {
+            info!("{} started a new session, closed its handles", peer);
+        }
+
+        self.pending.forget(peer);
+    }
+
+    /// Opens a handle for `peer`
+    fn open_handle(&mut self, peer: Peer, handle: Handle) -> Result<u64, Error> {
+        let session = self.sessions.get_mut(&peer).ok_or_else(no_session)?;
+
+        Ok(session.insert(handle))
+    }
+
+    /// Handle `fh` of `peer`
+    fn handle(&self, peer: Peer, fh: u64) -> Result<&Handle, Error> {
+        self.sessions
+            .get(&peer)
+            .and_then(|session| session.handles.get(&fh))
+            .ok_or_else(bad_handle)
+    }
+
+    /// File opened by `peer` as `fh`
+    fn file(&self, peer: Peer, fh: u64) -> Result<&fs::File, Error> {
+        match self.handle(peer, fh)? {
+            Handle::File(file) => Ok(file),
+            Handle::Dir(_) => Err(Error::from_raw_os_error(libc::EISDIR)),
+        }
+    }
 
-                Ok(MofosResponse::new_readdir(*id, Status::Ok, entries))
+    /// Directory opened by `peer` as `fh`
+    fn dir(&mut self, peer: Peer, fh: u64) -> Result<&mut Dir, Error> {
+        let handle = self
+            .sessions
+            .get_mut(&peer)
+            .and_then(|session| session.handles.get_mut(&fh))
+            .ok_or_else(bad_handle)?;
+
+        match handle {
+            Handle::Dir(dir) => Ok(dir),
+            Handle::File(_) => Err(Error::from_raw_os_error(libc::ENOTDIR)),
+        }
+    }
+
+    /// Closes the handle `fh` of `peer`, which must be a directory or not
+    /// depending on `dir`
+    fn close_handle(&mut self, peer: Peer, fh: u64, dir: bool) -> Result<(), Error> {
+        let session = self.sessions.get_mut(&peer).ok_or_else(bad_handle)?;
+
+        match session.handles.get(&fh) {
+            Some(Handle::Dir(_)) if dir => (),
+            Some(Handle::File(_)) if !dir => (),
+            _ => return Err(bad_handle()),
+        }
+
+        session.handles.remove(&fh);
+
+        Ok(())
+    }
+
+    fn send_response(&mut self, peer: Peer, resp: MofosResponse) -> Result<(), Error> {
+        let id = resp.id();
+        let bytes: Vec<u8> = resp.into();
+
+        self.listener.send(peer, id, bytes.as_slice())
+    }
+
+    /// Processes a request unless it is a retransmission of one that was
+    /// already answered, in which case the previous response is sent again.
+    /// Failures are reported in the response, there is none only for the
+    /// requests that do not expect one. Only a hello is processed for peers
+    /// without a session.
+    fn handle_request(&mut self, peer: Peer, req: &MofosRequest) -> Option<MofosResponse> {
+        match self.sessions.get_mut(&peer) {
+            Some(session) => session.last_seen = Instant::now(),
+
+            None if !matches!(req, MofosRequest::Hello { .. }) => {
+                warn!("refusing request from {} without a session", peer);
+                return req.failure(Status::from(&no_session()));
             }
 
-            MofosRequest::Write {
-                id,
-                path,
-                data,
-                offset,
-            } => {
-                if let Some(file) = self.files.get_mut(path) {
-                    if let Err(e) = file.write_all_at(data, *offset as u64) {
-                        unimplemented!("error writing file {}", e)
-                    } else {
-                        unimplemented!()
-                    }
+            None => (),
+        }
+
+        if req.is_idempotent() || !req.expects_response() {
+            return self.process_request(peer, req);
+        }
+
+        let id = req.id();
+
+        if let Some(resp) = self.pending.get(peer, id) {
+            debug!("replaying response to request {} from {}", id, peer);
+            return Some(resp.clone());
+        }
+
+        let resp = self.process_request(peer, req)?;
+
+        self.pending.insert(peer, id, resp.clone());
+
+        Some(resp)
+    }
+
+    /// Attributes of the file at `path`, symbolic links are described rather
+    /// than followed as lstat does
+    fn stat(&self, path: &str) -> Result<FileAttr, Error> {
+        let metadata = fs::symlink_metadata(self.jail.resolve_link(path)?)?;
+
+        Ok(FileAttr::from(&metadata))
+    }
+
+    /// Creates and opens the file at `path` for `peer`, returns its
+    /// attributes and handle
+    fn create(
+        &mut self,
+        peer: Peer,
+        path: &str,
+        mode: u32,
+        flags: u32,
+    ) -> Result<(FileAttr, u64), Error> {
+        let file = open_options(flags | libc::O_CREAT as u32)
+            .mode(mode)
+            .open(self.jail.resolve(path)?)?;
+        let attrs = FileAttr::from(&file.metadata()?);
+
+        Ok((attrs, self.open_handle(peer, Handle::File(file))?))
+    }
+
+    fn mknod(&self, path: &str, mode: u32, rdev: u32) -> Result<FileAttr, Error> {
+        let resolved = self.jail.resolve_link(path)?;
+        let cpath = c_path(&resolved)?;
+
+        if unsafe { libc::mknod(cpath.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(FileAttr::from(&fs::symlink_metadata(&resolved)?))
+    }
+
+    fn mkdir(&self, path: &str, mode: u32) -> Result<FileAttr, Error> {
+        let resolved = self.jail.resolve_link(path)?;
+
+        fs::DirBuilder::new().mode(mode).create(&resolved)?;
+
+        Ok(FileAttr::from(&fs::symlink_metadata(&resolved)?))
+    }
+
+    fn symlink(&self, path: &str, target: &str) -> Result<FileAttr, Error> {
+        let resolved = self.jail.resolve_link(path)?;
+
+        unix_fs::symlink(target, &resolved)?;
+
+        Ok(FileAttr::from(&fs::symlink_metadata(&resolved)?))
+    }
+
+    /// Links `newpath` to `path`, a symbolic link is linked rather than the
+    /// file it points to
+    fn link(&self, path: &str, newpath: &str) -> Result<FileAttr, Error> {
+        let original = self.jail.resolve_link(path)?;
+        let resolved = self.jail.resolve_link(newpath)?;
+
+        fs::hard_link(original, &resolved)?;
+
+        Ok(FileAttr::from(&fs::symlink_metadata(&resolved)?))
+    }
+
+    /// Renames `from` to `to`, symbolic links are renamed rather than followed
+    fn rename(&self, from: &str, to: &str, flags: u32) -> Result<(), Error> {
+        let from = self.jail.resolve_link(from)?;
+        let to = self.jail.resolve_link(to)?;
+
+        if flags == 0 {
+            fs::rename(from, to)
+        } else {
+            renameat2(&from, &to, flags)
+        }
+    }
+
+    /// Changes the attributes of the file at `path` that are set in `attrs`
+    /// Changes the attributes of the file at `path` for `peer`, setting its
+    /// size through the handle `fh` if it has the file open. Permissions to
+    /// write it were checked when it was opened, the file may not be
+    /// writable anymore.
+    fn set_attrs(
+        &self,
+        peer: Peer,
+        path: &str,
+        fh: Option<u64>,
+        attrs: &SetAttrs,
+    ) -> Result<FileAttr, Error> {
+        let resolved = self.jail.resolve_link(path)?;
+
+        if let Some(mode) = attrs.mode {
+            set_mode(&resolved, mode)?;
+        }
+
+        if attrs.uid.is_some() || attrs.gid.is_some() {
+            unix_fs::lchown(&resolved, attrs.uid, attrs.gid)?;
+        }
+
+        match (attrs.size, fh) {
+            (Some(size), Some(fh)) => self.file(peer, fh)?.set_len(size)?,
+
+            // truncate(2) follows links, but only within the root
+            (Some(size), None) => fs::OpenOptions::new()
+                .write(true)
+                .open(self.jail.resolve(path)?)?
+                .set_len(size)?,
+
+            (None, _) => (),
+        }
+
+        if attrs.atime.is_some() || attrs.mtime.is_some() {
+            set_times(&resolved, attrs.atime, attrs.mtime)?;
+        }
+
+        Ok(FileAttr::from(&fs::symlink_metadata(&resolved)?))
+    }
+
+    fn process_request(&mut self, peer: Peer, req: &MofosRequest) -> Option<MofosResponse> {
+        match req {
+            MofosRequest::Hello { id, hello } => {
+                let status = if hello.version == PROTOCOL_VERSION {
+                    self.start_session(peer, hello.session);
+                    Status::Ok
                 } else {
-                    Err(Error::new(ErrorKind::InvalidInput, "Unopened path"))
-                }
+                    warn!(
+                        "refusing {} speaking protocol version {}, we speak {}",
+                        peer, hello.version, PROTOCOL_VERSION
+                    );
+                    self.end_session(peer);
+                    Status::Err(libc::EPROTONOSUPPORT)
+                };
+
+                Some(MofosResponse::Hello(*id, status, server_hello()))
             }
 
-            MofosRequest::SetAttr { id, path, attrs } => {
-                unimplemented!("setattr for {} at {} with {:?}", id, path, attrs)
+            MofosRequest::GetAttr { id, path } => {
+                let resp = match self.stat(path) {
+                    Ok(attrs) => MofosResponse::new_get_attr(*id, attrs),
+                    Err(e) => MofosResponse::GetAttr(*id, Status::from(&e), FileAttr::default()),
+                };
+
+                Some(resp)
             }
 
-            MofosRequest::Read {
-                id,
-                path,
-                size,
-                offset,
-            } => {
-                if let Some(file) = self.files.get(path) {
-                    let buf: &mut [u8] = &mut [0u8; 1500];
-                    let read = file.read_at(&mut buf[0..*size as usize], *offset as u64)?;
-
-                    Ok(MofosResponse::new_read(
-                        *id,
-                        Status::Ok,
-                        Vec::from(&mut buf[0..read]),
-                    ))
+            MofosRequest::Lookup { id, parent, name } => {
+                let attrs = entry_path(parent, name).and_then(|path| self.stat(&path));
+                let resp = match attrs {
+                    Ok(attrs) => MofosResponse::new_lookup(*id, Status::Ok, attrs),
+                    Err(e) => MofosResponse::new_lookup(*id, Status::from(&e), FileAttr::default()),
+                };
+
+                Some(resp)
+            }
+
+            // every open gets its own handle, even of the same file
+            MofosRequest::Open { id, path, flags } => {
+                // the jail follows symlinks itself unless asked not to
+                let resolved = if *flags as i32 & libc::O_NOFOLLOW != 0 {
+                    self.jail.resolve_link(path)
                 } else {
-                    Err(Error::new(ErrorKind::InvalidInput, "unopened path"))
+                    self.jail.resolve(path)
+                };
+
+                let opened = resolved
+                    .and_then(|p| open_options(*flags).open(p))
+                    .and_then(|file| self.open_handle(peer, Handle::File(file)));
+
+                match opened {
+                    Ok(fh) => Some(MofosResponse::new_open(*id, Status::Ok, fh)),
+
+                    Err(e) => Some(MofosResponse::new_open(*id, Status::from(&e), 0)),
                 }
             }
 
-            // TODO: handle other request types
-            _ => Err(Error::new(
-                ErrorKind::Other,
-                "process_request: unimplemented",
-            )),
-        }
-    }
-}
+            MofosRequest::OpenDir { id, path, .. } => {
+                let opened = self.jail.resolve(path).and_then(|path| {
+                    // fails early on what is not a directory or cannot be read
+                    fs::read_dir(&path)?;
 
-#[cfg(test)]
-mod test {
-    extern crate mktemp;
+                    self.open_handle(peer, Handle::Dir(Dir::new(path)))
+                });
+                let resp = match opened {
+                    Ok(fh) => MofosResponse::OpenDir(*id, Status::Ok, fh),
 
-    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+                    Err(e) => MofosResponse::OpenDir(*id, Status::from(&e), 0),
+                };
 
-    use self::mktemp::Temp;
-    use super::MofosServer;
+                Some(resp)
+            }
 
-    const ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8080));
+            MofosRequest::Create {
+                id,
+                parent,
+                name,
+                mode,
+                flags,
+            } => {
+                let created = entry_path(parent, name)
+                    .and_then(|path| self.create(peer, &path, *mode, *flags));
+                let resp = match created {
+                    Ok((attrs, fh)) => MofosResponse::Create(*id, Status::Ok, attrs, fh),
+                    Err(e) => MofosResponse::Create(*id, Status::from(&e), FileAttr::default(), 0),
+                };
 
-    fn setup_test() -> (MofosServer, Temp) {
-        let temp = Temp::new_dir().expect("could not create temp dir");
-        let srv = MofosServer::new(ADDR, &temp.to_path_buf()).expect("unable to start server");
+                Some(resp)
+            }
 
-        (srv, temp)
-    }
+            MofosRequest::MkNod {
+                id,
+                parent,
+                name,
+                mode,
+                rdev,
+            } => {
+                let created =
+                    entry_path(parent, name).and_then(|path| self.mknod(&path, *mode, *rdev));
+                let resp = match created {
+                    Ok(attrs) => MofosResponse::MkNod(*id, Status::Ok, attrs),
+                    Err(e) => MofosResponse::MkNod(*id, Status::from(&e), FileAttr::default()),
+                };
 
-    #[test]
-    fn server_bind_test() {
-        let (srv, tmp) = setup_test();
+                Some(resp)
+            }
 
-        assert!(tmp.to_path_buf().exists());
+            MofosRequest::MkDir {
+                id,
+                parent,
+                name,
+                mode,
+            } => {
+                let created = entry_path(parent, name).and_then(|path| self.mkdir(&path, *mode));
+                let resp = match created {
+                    Ok(attrs) => MofosResponse::MkDir(*id, Status::Ok, attrs),
+                    Err(e) => MofosResponse::MkDir(*id, Status::from(&e), FileAttr::default()),
+                };
+
+                Some(resp)
+            }
+
+            // the entry itself is removed, not what a symbolic link points to
+            MofosRequest::Unlink { id, parent, name } => {
+                let removed = entry_path(parent, name)
+                    .and_then(|path| self.jail.resolve_link(&path))
+                    .and_then(fs::remove_file);
+
+                Some(MofosResponse::Unlink(*id, status_of(removed)))
+            }
+
+            MofosRequest::Rmdir { id, parent, name } => {
+                let removed = entry_path(parent, name)
+                    .and_then(|path| self.jail.resolve_link(&path))
+                    .and_then(fs::remove_dir);
+
+                Some(MofosResponse::Rmdir(*id, status_of(removed)))
+            }
+
+            MofosRequest::Rename {
+                id,
+                parent,
+                name,
+                newparent,
+                newname,
+                flags,
+            } => {
+                let renamed = entry_path(parent, name).and_then(|from| {
+                    let to = entry_path(newparent, newname)?;
+
+                    self.rename(&from, &to, *flags)
+                });
+
+                Some(MofosResponse::Rename(*id, status_of(renamed)))
+            }
+
+            MofosRequest::GetXattr {
+                id,
+                path,
+                name,
+                size,
+            } => {
+                let value = self
+                    .jail
+                    .resolve_link(path)
+                    .and_then(|path| xattr::get(&path, name, *size));
+                let resp = match value {
+                    Ok(value) => MofosResponse::GetXattr(*id, Status::Ok, value),
+                    Err(e) => MofosResponse::GetXattr(*id, Status::from(&e), Xattr::default()),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::SetXattr {
+                id,
+                path,
+                name,
+                value,
+                flags,
+            } => {
+                let set = self
+                    .jail
+                    .resolve_link(path)
+                    .and_then(|path| xattr::set(&path, name, value, *flags));
+
+                Some(MofosResponse::SetXattr(*id, status_of(set)))
+            }
+
+            MofosRequest::ListXattr { id, path, size } => {
+                let names = self
+                    .jail
+                    .resolve_link(path)
+                    .and_then(|path| xattr::list(&path, *size));
+                let resp = match names {
+                    Ok(names) => MofosResponse::ListXattr(*id, Status::Ok, names),
+                    Err(e) => MofosResponse::ListXattr(*id, Status::from(&e), Xattr::default()),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::RemoveXattr { id, path, name } => {
+                let removed = self
+                    .jail
+                    .resolve_link(path)
+                    .and_then(|path| xattr::remove(&path, name));
+
+                Some(MofosResponse::RemoveXattr(*id, status_of(removed)))
+            }
+
+            MofosRequest::StatFs { id } => {
+                let resp = match statvfs(self.jail.root()) {
+                    Ok(stats) => MofosResponse::StatFs(*id, Status::Ok, stats),
+                    Err(e) => MofosResponse::StatFs(*id, Status::from(&e), FsStats::default()),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::ReadLink { id, path } => {
+                let target = self
+                    .jail
+                    .resolve_link(path)
+                    .and_then(fs::read_link)
+                    .and_then(|target| {
+                        target.into_os_string().into_string().map_err(|_| {
+                            Error::new(ErrorKind::InvalidData, "link target is not valid UTF-8")
+                        })
+                    });
+                let resp = match target {
+                    Ok(target) => MofosResponse::ReadLink(*id, Status::Ok, target),
+                    Err(e) => MofosResponse::ReadLink(*id, Status::from(&e), String::new()),
+                };
+
+                Some(resp)
+            }
+
+            // the target is only ever followed through the jail, links
+            // pointing outside of the exported directory cannot be used to
+            // reach it
+            MofosRequest::Symlink {
+                id,
+                parent,
+                name,
+                target,
+            } => {
+                let created = entry_path(parent, name).and_then(|path| self.symlink(&path, target));
+                let resp = match created {
+                    Ok(attrs) => MofosResponse::Symlink(*id, Status::Ok, attrs),
+                    Err(e) => MofosResponse::Symlink(*id, Status::from(&e), FileAttr::default()),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::Link {
+                id,
+                path,
+                newparent,
+                newname,
+            } => {
+                let linked =
+                    entry_path(newparent, newname).and_then(|newpath| self.link(path, &newpath));
+                let resp = match linked {
+                    Ok(attrs) => MofosResponse::Link(*id, Status::Ok, attrs),
+                    Err(e) => MofosResponse::Link(*id, Status::from(&e), FileAttr::default()),
+                };
+
+                Some(resp)
+            }
+
+            // writes go straight to the file, there is nothing to flush
+            MofosRequest::Flush { id, fh } => {
+                Some(MofosResponse::Flush(*id, status_of(self.file(peer, *fh))))
+            }
+
+            MofosRequest::Fsync { id, fh, datasync } => {
+                let synced = self.file(peer, *fh).and_then(|file| {
+                    if *datasync {
+                        file.sync_data()
+                    } else {
+                        file.sync_all()
+                    }
+                });
+
+                Some(MofosResponse::Fsync(*id, status_of(synced)))
+            }
+
+            MofosRequest::Release { id, fh } => {
+                let closed = self.close_handle(peer, *fh, false);
+
+                Some(MofosResponse::Release(*id, status_of(closed)))
+            }
+
+            MofosRequest::ReleaseDir { id, fh } => {
+                let closed = self.close_handle(peer, *fh, true);
+
+                Some(MofosResponse::ReleaseDir(*id, status_of(closed)))
+            }
+
+            MofosRequest::Readdir { id, fh, offset } => {
+                let resp = match self.dir(peer, *fh).and_then(|dir| dir.read(*offset)) {
+                    Ok(entries) => MofosResponse::new_readdir(*id, Status::Ok, entries),
+                    Err(e) => MofosResponse::new_readdir(*id, Status::from(&e), Vec::new()),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::Write {
+                id,
+                fh,
+                data,
+                offset,
+            } => {
+                let written = self
+                    .file(peer, *fh)
+                    .and_then(|file| file.write_all_at(data, *offset as u64));
+                let resp = match written {
+                    Ok(()) => MofosResponse::Write(*id, Status::Ok, data.len() as u32),
+                    Err(e) => MofosResponse::Write(*id, Status::from(&e), 0),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::SetAttr {
+                id,
+                path,
+                fh,
+                attrs,
+            } => {
+                let resp = match self.set_attrs(peer, path, *fh, attrs) {
+                    Ok(attrs) => MofosResponse::SetAttr(*id, Status::Ok, attrs),
+                    Err(e) => MofosResponse::SetAttr(*id, Status::from(&e), FileAttr::default()),
+                };
+
+                Some(resp)
+            }
+
+            MofosRequest::Read {
+                id,
+                fh,
+                size,
+                offset,
+            } => {
+                let file = match self.file(peer, *fh) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        return Some(MofosResponse::new_read(*id, Status::from(&e), Vec::new()))
+                    }
+                };
+                let mut buf = vec![0u8; (*size as usize).min(MAX_READ)];
+
+                match file.read_at(&mut buf, *offset as u64) {
+                    Ok(read) => {
+                        buf.truncate(read);
+                        Some(MofosResponse::new_read(*id, Status::Ok, buf))
+                    }
+
+                    Err(e) => Some(MofosResponse::new_read(*id, Status::from(&e), Vec::new())),
+                }
+            }
+
+            // the session was refreshed by receiving it
+            MofosRequest::KeepAlive { id } => Some(MofosResponse::KeepAlive(*id, Status::Ok)),
+
+            // no response is expected
+            MofosRequest::Exit { .. } => {
+                self.end_session(peer);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate libc;
+    extern crate mktemp;
+
+    use std::collections::HashSet;
+    use std::fs;
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use self::mktemp::Temp;
+    use super::super::proto::{
+        Hello, MofosRequest, MofosResponse, SetAttrs, Status, Timestamp, Type, Xattr,
+        PROTOCOL_VERSION,
+    };
+    use super::super::transport::{Peer, UdpListener, DEFAULT_MTU};
+    use super::{MofosServer, ReplyCache, SESSION_TIMEOUT};
+
+    const ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+    const CLIENT: Peer = Peer::Net(SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::new(127, 0, 0, 1),
+        4000,
+    )));
+    const OTHER_CLIENT: Peer = Peer::Net(SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::new(127, 0, 0, 1),
+        4001,
+    )));
+
+    fn setup_test() -> (MofosServer, Temp) {
+        let temp = Temp::new_dir().expect("could not create temp dir");
+        let listener = UdpListener::bind(ADDR, DEFAULT_MTU, None).expect("unable to bind");
+        let mut srv = MofosServer::new(Box::new(listener), &temp.to_path_buf())
+            .expect("unable to start server");
+
+        srv.start_session(CLIENT, 0);
+
+        (srv, temp)
+    }
+
+    #[test]
+    fn server_bind_test() {
+        let (_srv, tmp) = setup_test();
+
+        assert!(tmp.to_path_buf().exists());
     }
 
     #[test]
     fn server_finds_file_test() {
-        let (srv, tmp) = setup_test();
-        let path = tmp.to_path_buf();
+        let (mut srv, tmp) = setup_test();
+        let root = tmp.to_path_buf();
+
+        fs::create_dir(root.join("dir")).expect("failed to create dir");
+        fs::write(root.join("dir/file"), b"content").expect("failed to create file");
+
+        let metadata = fs::metadata(root.join("dir/file")).unwrap();
+        let lookup = |id, parent: &str, name: &str| MofosRequest::Lookup {
+            id,
+            parent: String::from(parent),
+            name: String::from(name),
+        };
+
+        match srv.handle_request(CLIENT, &lookup(1, "dir", "file")) {
+            Some(MofosResponse::Lookup(1, Status::Ok, attrs)) => {
+                assert_eq!(attrs.kind(), Type::File);
+                assert_eq!(attrs.file_id(), Some((metadata.dev(), metadata.ino())));
+            }
+            _ => panic!("lookup failed"),
+        }
+
+        let getattr = |id, path: &str| MofosRequest::GetAttr {
+            id,
+            path: String::from(path),
+        };
+
+        match srv.handle_request(CLIENT, &getattr(2, "dir")) {
+            Some(MofosResponse::GetAttr(2, Status::Ok, attrs)) => assert!(attrs.is_dir()),
+            _ => panic!("getattr failed"),
+        }
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &lookup(3, "dir", "missing")),
+            Some(MofosResponse::Lookup(3, Status::Err(libc::ENOENT), _))
+        ));
+
+        // nothing is found outside of the exported directory
+        assert!(matches!(
+            srv.handle_request(CLIENT, &getattr(4, "../")),
+            Some(MofosResponse::GetAttr(4, Status::Err(libc::EACCES), _))
+        ));
+    }
+
+    #[test]
+    fn reply_cache_evicts_oldest_test() {
+        let mut cache = ReplyCache::new(2);
+
+        cache.insert(CLIENT, 1, MofosResponse::new_open(1, Status::Ok, 1));
+        cache.insert(CLIENT, 2, MofosResponse::new_open(2, Status::Ok, 2));
+        cache.insert(CLIENT, 3, MofosResponse::new_open(3, Status::Ok, 3));
+
+        assert!(cache.get(CLIENT, 1).is_none());
+        assert_eq!(cache.get(CLIENT, 2).map(|r| r.id()), Some(2));
+        assert_eq!(cache.get(CLIENT, 3).map(|r| r.id()), Some(3));
+    }
+
+    #[test]
+    fn reply_cache_is_per_client_test() {
+        let mut cache = ReplyCache::new(2);
+
+        cache.insert(CLIENT, 1, MofosResponse::new_open(1, Status::Ok, 1));
+
+        assert!(cache.get(CLIENT, 1).is_some());
+        assert!(cache.get(OTHER_CLIENT, 1).is_none());
+    }
+
+    #[test]
+    fn server_replays_lost_reply_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        fs::write(&file, b"content").expect("failed to create file");
+
+        let req = MofosRequest::Open {
+            id: 1,
+            path: String::from("file"),
+            flags: 0,
+        };
+        let fh = match srv.handle_request(CLIENT, &req) {
+            Some(MofosResponse::Open(1, Status::Ok, fh)) => fh,
+            _ => panic!("open failed"),
+        };
+
+        // the reply to the first open was lost and the file vanished before
+        // the client retransmitted, the retransmission must not be executed
+        fs::remove_file(&file).expect("failed to remove file");
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::Open(1, Status::Ok, replayed)) if replayed == fh
+        ));
+    }
+
+    #[test]
+    fn server_forgets_replies_of_old_session_test() {
+        let (mut srv, tmp) = setup_test();
+        let hello = |session| MofosRequest::Hello {
+            id: 1,
+            hello: Hello {
+                version: PROTOCOL_VERSION,
+                session,
+                ..Default::default()
+            },
+        };
+        let mkdir = |name: &str| MofosRequest::MkDir {
+            id: 2,
+            parent: String::new(),
+            name: String::from(name),
+            mode: 0o755,
+        };
+
+        srv.handle_request(CLIENT, &hello(1)).expect("hello failed");
+        srv.handle_request(CLIENT, &mkdir("first"))
+            .expect("mkdir failed");
+
+        // a retransmitted hello does not start over
+        srv.handle_request(CLIENT, &hello(1)).expect("hello failed");
+        srv.handle_request(CLIENT, &mkdir("replayed"))
+            .expect("mkdir failed");
+        assert!(!tmp.to_path_buf().join("replayed").exists());
+
+        // the client mounted again from the same address, reusing its ids
+        srv.handle_request(CLIENT, &hello(2)).expect("hello failed");
+        assert!(matches!(
+            srv.handle_request(CLIENT, &mkdir("second")),
+            Some(MofosResponse::MkDir(2, Status::Ok, _))
+        ));
+        assert!(tmp.to_path_buf().join("second").is_dir());
+    }
+
+    #[test]
+    fn server_does_not_cache_idempotent_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        fs::write(&file, b"content").expect("failed to create file");
+
+        let req = MofosRequest::GetAttr {
+            id: 1,
+            path: String::from("file"),
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::GetAttr(1, Status::Ok, _))
+        ));
+
+        fs::remove_file(&file).expect("failed to remove file");
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::GetAttr(1, Status::Err(libc::ENOENT), _))
+        ));
+    }
+
+    #[test]
+    fn server_confines_paths_test() {
+        let (mut srv, tmp) = setup_test();
+        let outside = tmp.to_path_buf().join("..");
+
+        for path in &["/etc/passwd", "../", "a/../../"] {
+            let req = MofosRequest::GetAttr {
+                id: 1,
+                path: String::from(*path),
+            };
+
+            assert!(matches!(
+                srv.handle_request(CLIENT, &req),
+                Some(MofosResponse::GetAttr(1, Status::Err(libc::EACCES), _))
+            ));
+        }
+
+        symlink(outside, tmp.to_path_buf().join("up")).expect("failed to create link");
+
+        let req = MofosRequest::OpenDir {
+            id: 2,
+            path: String::from("up"),
+            flags: 0,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::OpenDir(2, Status::Err(libc::EACCES), _))
+        ));
+    }
+
+    #[test]
+    fn server_lookup_test() {
+        let (mut srv, tmp) = setup_test();
+
+        fs::create_dir(tmp.to_path_buf().join("dir")).expect("failed to create dir");
+        fs::write(tmp.to_path_buf().join("dir/file"), b"content").expect("failed to create file");
+
+        let lookup = |id, parent: &str, name: &str| MofosRequest::Lookup {
+            id,
+            parent: String::from(parent),
+            name: String::from(name),
+        };
+
+        match srv.handle_request(CLIENT, &lookup(1, "", "dir")) {
+            Some(MofosResponse::Lookup(1, Status::Ok, attrs)) => assert!(attrs.is_dir()),
+            _ => panic!("lookup of dir failed"),
+        }
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &lookup(2, "dir", "file")),
+            Some(MofosResponse::Lookup(2, Status::Ok, _))
+        ));
+        assert!(matches!(
+            srv.handle_request(CLIENT, &lookup(3, "dir", "missing")),
+            Some(MofosResponse::Lookup(3, Status::Err(libc::ENOENT), _))
+        ));
+        assert!(matches!(
+            srv.handle_request(CLIENT, &lookup(4, "dir", "..")),
+            Some(MofosResponse::Lookup(4, Status::Err(libc::EACCES), _))
+        ));
+    }
+
+    #[test]
+    fn server_readdir_pages_test() {
+        let (mut srv, tmp) = setup_test();
+        let dir = tmp.to_path_buf().join("dir");
+        let count = 10_000;
+
+        fs::create_dir(&dir).expect("failed to create dir");
+
+        for i in 0..count {
+            fs::write(dir.join(format!("file{}", i)), b"").expect("failed to create file");
+        }
+
+        let opendir = MofosRequest::OpenDir {
+            id: 0,
+            path: String::from("dir"),
+            flags: 0,
+        };
+        let fh = match srv.handle_request(CLIENT, &opendir) {
+            Some(MofosResponse::OpenDir(0, Status::Ok, fh)) => fh,
+            _ => panic!("opendir failed"),
+        };
+        let mut names = HashSet::new();
+        let mut offset = 0;
+        let mut pages = 0;
+
+        loop {
+            let req = MofosRequest::Readdir {
+                id: pages + 1,
+                fh,
+                offset,
+            };
+            let entries = match srv.handle_request(CLIENT, &req) {
+                Some(MofosResponse::Readdir(_, Status::Ok, entries)) => entries,
+                _ => panic!("readdir failed"),
+            };
+
+            match entries.last() {
+                Some(last) => offset = last.offset(),
+                None => break,
+            }
+
+            for entry in entries {
+                assert!(
+                    entry.name().starts_with("file"),
+                    "not a name: {}",
+                    entry.name()
+                );
+                assert!(names.insert(String::from(entry.name())), "duplicate entry");
+            }
+
+            pages += 1;
+        }
+
+        assert_eq!(names.len(), count);
+        assert!(pages > 1);
+    }
+
+    #[test]
+    fn server_readdir_rewind_test() {
+        let (mut srv, tmp) = setup_test();
+        let dir = tmp.to_path_buf();
+
+        fs::write(dir.join("first"), b"").expect("failed to create file");
+
+        let opendir = MofosRequest::OpenDir {
+            id: 0,
+            path: String::new(),
+            flags: 0,
+        };
+        let fh = match srv.handle_request(CLIENT, &opendir) {
+            Some(MofosResponse::OpenDir(0, Status::Ok, fh)) => fh,
+            _ => panic!("opendir failed"),
+        };
+        let names = |srv: &mut MofosServer, id, offset| match srv
+            .handle_request(CLIENT, &MofosRequest::Readdir { id, fh, offset })
+        {
+            Some(MofosResponse::Readdir(_, Status::Ok, entries)) => entries
+                .iter()
+                .map(|entry| String::from(entry.name()))
+                .collect::<Vec<_>>(),
+            _ => panic!("readdir failed"),
+        };
+
+        assert_eq!(names(&mut srv, 1, 0), vec!["first"]);
+
+        // the listing is kept until reading starts over
+        fs::write(dir.join("second"), b"").expect("failed to create file");
+        assert!(names(&mut srv, 2, 1).is_empty());
+        assert_eq!(names(&mut srv, 3, 0).len(), 2);
+    }
+
+    #[test]
+    fn server_readdir_missing_test() {
+        let (mut srv, _tmp) = setup_test();
+        let req = MofosRequest::OpenDir {
+            id: 1,
+            path: String::from("missing"),
+            flags: 0,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::OpenDir(1, Status::Err(libc::ENOENT), _))
+        ));
+    }
+
+    #[test]
+    fn server_open_read_release_test() {
+        let (mut srv, tmp) = setup_test();
+        let path = String::from("file");
+
+        fs::write(tmp.to_path_buf().join("file"), b"content").expect("failed to create file");
+
+        let read = |id, fh, offset| MofosRequest::Read {
+            id,
+            fh,
+            size: 4,
+            offset,
+        };
+        let mut fhs = Vec::new();
+
+        for id in 1..3 {
+            let req = MofosRequest::Open {
+                id,
+                path: path.clone(),
+                flags: 0,
+            };
+
+            match srv.handle_request(CLIENT, &req) {
+                Some(MofosResponse::Open(_, Status::Ok, fh)) => fhs.push(fh),
+                _ => panic!("open failed"),
+            }
+        }
+
+        // every open gets its own handle
+        assert_ne!(fhs[0], fhs[1]);
+
+        match srv.handle_request(CLIENT, &read(3, fhs[0], 4)) {
+            Some(MofosResponse::Read(3, Status::Ok, data)) => assert_eq!(data, b"ent"),
+            _ => panic!("read failed"),
+        }
+
+        // handles are private to the client that opened them
+        srv.start_session(OTHER_CLIENT, 0);
+        assert!(matches!(
+            srv.handle_request(OTHER_CLIENT, &read(4, fhs[0], 0)),
+            Some(MofosResponse::Read(4, Status::Err(libc::EBADF), _))
+        ));
+
+        let release = MofosRequest::Release { id: 5, fh: fhs[0] };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &release),
+            Some(MofosResponse::Release(5, Status::Ok))
+        ));
+        assert!(matches!(
+            srv.handle_request(CLIENT, &read(6, fhs[0], 0)),
+            Some(MofosResponse::Read(6, Status::Err(libc::EBADF), _))
+        ));
+        assert!(matches!(
+            srv.handle_request(CLIENT, &read(7, fhs[1], 0)),
+            Some(MofosResponse::Read(7, Status::Ok, _))
+        ));
+    }
+
+    #[test]
+    fn server_write_path_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+        let path = String::from("file");
+
+        let create = MofosRequest::Create {
+            id: 1,
+            parent: String::new(),
+            name: path.clone(),
+            mode: 0o644,
+            flags: libc::O_WRONLY as u32,
+        };
+
+        let fh = match srv.handle_request(CLIENT, &create) {
+            Some(MofosResponse::Create(1, Status::Ok, _, fh)) => fh,
+            _ => panic!("create failed"),
+        };
+
+        let write = MofosRequest::Write {
+            id: 2,
+            fh,
+            data: Vec::from(&b"content"[..]),
+            offset: 0,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &write),
+            Some(MofosResponse::Write(2, Status::Ok, 7))
+        ));
+
+        let fsync = MofosRequest::Fsync {
+            id: 3,
+            fh,
+            datasync: false,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &fsync),
+            Some(MofosResponse::Fsync(3, Status::Ok))
+        ));
+        assert_eq!(fs::read(&file).unwrap(), b"content");
+
+        let truncate = MofosRequest::SetAttr {
+            id: 4,
+            path,
+            fh: None,
+            attrs: SetAttrs {
+                size: Some(4),
+                ..Default::default()
+            },
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &truncate),
+            Some(MofosResponse::SetAttr(4, Status::Ok, _))
+        ));
+        assert_eq!(fs::read(&file).unwrap(), b"cont");
+    }
+
+    #[test]
+    fn server_open_flags_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        fs::write(&file, b"content").expect("failed to create file");
+
+        let open = MofosRequest::Open {
+            id: 1,
+            path: String::from("file"),
+            flags: (libc::O_WRONLY | libc::O_TRUNC) as u32,
+        };
+
+        srv.handle_request(CLIENT, &open).expect("open failed");
+        assert_eq!(fs::read(&file).unwrap(), b"");
+
+        // creating an existing file exclusively fails
+        let create = MofosRequest::Create {
+            id: 2,
+            parent: String::new(),
+            name: String::from("file"),
+            mode: 0o644,
+            flags: (libc::O_WRONLY | libc::O_EXCL) as u32,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &create),
+            Some(MofosResponse::Create(2, Status::Err(libc::EEXIST), ..))
+        ));
+
+        // flags the standard library does not model reach open(2)
+        symlink("file", tmp.to_path_buf().join("link")).expect("failed to link");
+
+        let nofollow = MofosRequest::Open {
+            id: 3,
+            path: String::from("link"),
+            flags: libc::O_NOFOLLOW as u32,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &nofollow),
+            Some(MofosResponse::Open(3, Status::Err(libc::ELOOP), _))
+        ));
+    }
+
+    #[test]
+    fn server_read_only_create_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        let create = MofosRequest::Create {
+            id: 1,
+            parent: String::new(),
+            name: String::from("file"),
+            mode: 0o444,
+            flags: libc::O_RDONLY as u32,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &create),
+            Some(MofosResponse::Create(1, Status::Ok, ..))
+        ));
+        assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o444);
+    }
+
+    #[test]
+    fn server_read_only_truncate_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        fs::write(&file, b"content").expect("failed to create file");
+
+        let open = MofosRequest::Open {
+            id: 1,
+            path: String::from("file"),
+            flags: (libc::O_RDONLY | libc::O_TRUNC) as u32,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &open),
+            Some(MofosResponse::Open(1, Status::Ok, _))
+        ));
+        assert_eq!(fs::read(&file).unwrap(), b"");
+    }
+
+    #[test]
+    fn server_set_attrs_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        fs::write(&file, b"content").expect("failed to create file");
+
+        let metadata = fs::metadata(&file).unwrap();
+        let attrs = SetAttrs {
+            mode: Some(0o600),
+            // chown to ourselves is always allowed
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            mtime: Some(Timestamp {
+                sec: 1_000_000_000,
+                nsec: 5,
+            }),
+            ..Default::default()
+        };
+        let req = MofosRequest::SetAttr {
+            id: 1,
+            path: String::from("file"),
+            fh: None,
+            attrs,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::SetAttr(1, Status::Ok, _))
+        ));
+
+        let changed = fs::metadata(&file).unwrap();
+
+        assert_eq!(changed.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(changed.mtime(), 1_000_000_000);
+        assert_eq!(changed.mtime_nsec(), 5);
+        assert_eq!(changed.atime(), metadata.atime());
+        assert_eq!(changed.len(), 7);
+    }
+
+    #[test]
+    fn server_set_attrs_through_handle_test() {
+        let (mut srv, tmp) = setup_test();
+        let file = tmp.to_path_buf().join("file");
+
+        // the file may be written through the handle but no longer opened
+        // for writing, as after open(2) with O_CREAT and mode 0444
+        let create = MofosRequest::Create {
+            id: 1,
+            parent: String::new(),
+            name: String::from("file"),
+            mode: 0o444,
+            flags: libc::O_WRONLY as u32,
+        };
+
+        let fh = match srv.handle_request(CLIENT, &create) {
+            Some(MofosResponse::Create(1, Status::Ok, _, fh)) => fh,
+            _ => panic!("create failed"),
+        };
+
+        let truncate = MofosRequest::SetAttr {
+            id: 2,
+            path: String::from("file"),
+            fh: Some(fh),
+            attrs: SetAttrs {
+                size: Some(4),
+                ..Default::default()
+            },
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &truncate),
+            Some(MofosResponse::SetAttr(2, Status::Ok, _))
+        ));
+        assert_eq!(fs::metadata(&file).unwrap().len(), 4);
+
+        let stale = MofosRequest::SetAttr {
+            id: 3,
+            path: String::from("file"),
+            fh: Some(fh + 1),
+            attrs: SetAttrs {
+                size: Some(0),
+                ..Default::default()
+            },
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &stale),
+            Some(MofosResponse::SetAttr(3, Status::Err(libc::EBADF), _))
+        ));
+    }
+
+    #[test]
+    fn server_set_attrs_on_link_test() {
+        let (mut srv, tmp) = setup_test();
+        let root = tmp.to_path_buf();
+
+        fs::write(root.join("file"), b"content").expect("failed to create file");
+        symlink("file", root.join("link")).expect("failed to create link");
+
+        let file = fs::metadata(root.join("file")).unwrap();
+        let link = fs::symlink_metadata(root.join("link")).unwrap();
+
+        // as with touch -h and chown -h, the link changes and not its target
+        let req = MofosRequest::SetAttr {
+            id: 1,
+            path: String::from("link"),
+            fh: None,
+            attrs: SetAttrs {
+                uid: Some(link.uid()),
+                gid: Some(link.gid()),
+                mtime: Some(Timestamp {
+                    sec: 1_000_000_000,
+                    nsec: 0,
+                }),
+                ..Default::default()
+            },
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &req),
+            Some(MofosResponse::SetAttr(1, Status::Ok, _))
+        ));
+        assert_eq!(
+            fs::symlink_metadata(root.join("link")).unwrap().mtime(),
+            1_000_000_000
+        );
+        assert_eq!(
+            fs::metadata(root.join("file")).unwrap().mtime(),
+            file.mtime()
+        );
+    }
+
+    #[test]
+    fn server_directory_ops_test() {
+        let (mut srv, tmp) = setup_test();
+        let root = tmp.to_path_buf();
+        let entry = |name: &str| (String::new(), String::from(name));
+
+        let (parent, name) = entry("dir");
+        let mkdir = MofosRequest::MkDir {
+            id: 1,
+            parent,
+            name,
+            mode: 0o755,
+        };
+
+        match srv.handle_request(CLIENT, &mkdir) {
+            Some(MofosResponse::MkDir(1, Status::Ok, attrs)) => assert!(attrs.is_dir()),
+            _ => panic!("mkdir failed"),
+        }
+
+        let mknod = MofosRequest::MkNod {
+            id: 2,
+            parent: String::from("dir"),
+            name: String::from("file"),
+            mode: libc::S_IFREG | 0o644,
+            rdev: 0,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &mknod),
+            Some(MofosResponse::MkNod(2, Status::Ok, _))
+        ));
+        assert!(root.join("dir/file").is_file());
+
+        let (parent, name) = entry("dir");
+        let rmdir = MofosRequest::Rmdir {
+            id: 3,
+            parent,
+            name,
+        };
+
+        // the directory is not empty
+        assert!(matches!(
+            srv.handle_request(CLIENT, &rmdir),
+            Some(MofosResponse::Rmdir(3, Status::Err(libc::ENOTEMPTY)))
+        ));
+
+        let unlink = MofosRequest::Unlink {
+            id: 4,
+            parent: String::from("dir"),
+            name: String::from("file"),
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &unlink),
+            Some(MofosResponse::Unlink(4, Status::Ok))
+        ));
+
+        let (parent, name) = entry("dir");
+        let rmdir = MofosRequest::Rmdir {
+            id: 5,
+            parent,
+            name,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &rmdir),
+            Some(MofosResponse::Rmdir(5, Status::Ok))
+        ));
+        assert!(!root.join("dir").exists());
+    }
+
+    #[test]
+    fn server_rename_test() {
+        let (mut srv, tmp) = setup_test();
+        let root = tmp.to_path_buf();
+        let rename = |id, name: &str, newname: &str, flags| MofosRequest::Rename {
+            id,
+            parent: String::new(),
+            name: String::from(name),
+            newparent: String::new(),
+            newname: String::from(newname),
+            flags,
+        };
+
+        fs::write(root.join("a"), b"a").expect("failed to create file");
+        fs::write(root.join("b"), b"b").expect("failed to create file");
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &rename(1, "a", "c", 0)),
+            Some(MofosResponse::Rename(1, Status::Ok))
+        ));
+        assert_eq!(fs::read(root.join("c")).unwrap(), b"a");
+
+        // the destination exists
+        assert!(matches!(
+            srv.handle_request(CLIENT, &rename(2, "c", "b", libc::RENAME_NOREPLACE)),
+            Some(MofosResponse::Rename(2, Status::Err(libc::EEXIST)))
+        ));
+        assert!(matches!(
+            srv.handle_request(CLIENT, &rename(3, "c", "b", libc::RENAME_EXCHANGE)),
+            Some(MofosResponse::Rename(3, Status::Ok))
+        ));
+        assert_eq!(fs::read(root.join("b")).unwrap(), b"a");
+        assert_eq!(fs::read(root.join("c")).unwrap(), b"b");
+
+        // names may not smuggle paths in
+        assert!(matches!(
+            srv.handle_request(CLIENT, &rename(4, "c", "../c", 0)),
+            Some(MofosResponse::Rename(4, Status::Err(libc::EINVAL)))
+        ));
+    }
+
+    #[test]
+    fn server_links_test() {
+        let (mut srv, tmp) = setup_test();
+        let root = tmp.to_path_buf();
+
+        fs::write(root.join("file"), b"content").expect("failed to create file");
+
+        let symlink = |id, name: &str, target: &str| MofosRequest::Symlink {
+            id,
+            parent: String::new(),
+            name: String::from(name),
+            target: String::from(target),
+        };
+
+        match srv.handle_request(CLIENT, &symlink(1, "link", "file")) {
+            Some(MofosResponse::Symlink(1, Status::Ok, attrs)) => {
+                assert_eq!(attrs.kind(), Type::Link)
+            }
+            _ => panic!("symlink failed"),
+        }
+
+        let readlink = MofosRequest::ReadLink {
+            id: 2,
+            path: String::from("link"),
+        };
+
+        match srv.handle_request(CLIENT, &readlink) {
+            Some(MofosResponse::ReadLink(2, Status::Ok, target)) => assert_eq!(target, "file"),
+            _ => panic!("readlink failed"),
+        }
+
+        let link = MofosRequest::Link {
+            id: 3,
+            path: String::from("file"),
+            newparent: String::new(),
+            newname: String::from("hard"),
+        };
+
+        let file = fs::metadata(root.join("file")).unwrap();
+
+        // the client gives both names the same inode from this
+        match srv.handle_request(CLIENT, &link) {
+            Some(MofosResponse::Link(3, Status::Ok, attrs)) => {
+                assert_eq!(attrs.file_id(), Some((file.dev(), file.ino())))
+            }
+            _ => panic!("link failed"),
+        }
+        assert_eq!(fs::metadata(root.join("file")).unwrap().nlink(), 2);
+        assert_eq!(fs::read(root.join("hard")).unwrap(), b"content");
+
+        // links may point anywhere but are not followed out of the root
+        assert!(matches!(
+            srv.handle_request(CLIENT, &symlink(4, "escape", "/etc/passwd")),
+            Some(MofosResponse::Symlink(4, Status::Ok, _))
+        ));
+
+        let open = MofosRequest::Open {
+            id: 5,
+            path: String::from("escape"),
+            flags: libc::O_RDONLY as u32,
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &open),
+            Some(MofosResponse::Open(5, Status::Err(libc::EACCES), _))
+        ));
+    }
+
+    #[test]
+    fn server_xattr_test() {
+        let (mut srv, tmp) = setup_test();
+
+        fs::write(tmp.to_path_buf().join("file"), b"").expect("failed to create file");
+
+        let path = String::from("file");
+        let name = String::from("user.mofos");
+        let get = |id, size| MofosRequest::GetXattr {
+            id,
+            path: path.clone(),
+            name: name.clone(),
+            size,
+        };
+        let set = MofosRequest::SetXattr {
+            id: 1,
+            path: path.clone(),
+            name: name.clone(),
+            value: b"value".to_vec(),
+            flags: 0,
+        };
+
+        match srv.handle_request(CLIENT, &set) {
+            Some(MofosResponse::SetXattr(1, Status::Ok)) => (),
+            // not every filesystem the tests run on has user attributes
+            Some(MofosResponse::SetXattr(1, Status::Err(libc::ENOTSUP))) => return,
+            _ => panic!("setxattr failed"),
+        }
+
+        // a size of 0 probes for the size of the value
+        assert!(matches!(
+            srv.handle_request(CLIENT, &get(2, 0)),
+            Some(MofosResponse::GetXattr(2, Status::Ok, Xattr::Size(5)))
+        ));
+
+        match srv.handle_request(CLIENT, &get(3, 64)) {
+            Some(MofosResponse::GetXattr(3, Status::Ok, Xattr::Data(value))) => {
+                assert_eq!(value, b"value")
+            }
+            _ => panic!("getxattr failed"),
+        }
+        assert!(matches!(
+            srv.handle_request(CLIENT, &get(4, 2)),
+            Some(MofosResponse::GetXattr(4, Status::Err(libc::ERANGE), _))
+        ));
+
+        let list = MofosRequest::ListXattr {
+            id: 5,
+            path: path.clone(),
+            size: 1024,
+        };
+
+        match srv.handle_request(CLIENT, &list) {
+            Some(MofosResponse::ListXattr(5, Status::Ok, Xattr::Data(names))) => {
+                assert!(names.split(|&b| b == 0).any(|n| n == b"user.mofos"))
+            }
+            _ => panic!("listxattr failed"),
+        }
+
+        let remove = MofosRequest::RemoveXattr {
+            id: 6,
+            path: path.clone(),
+            name: name.clone(),
+        };
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &remove),
+            Some(MofosResponse::RemoveXattr(6, Status::Ok))
+        ));
+        assert!(matches!(
+            srv.handle_request(CLIENT, &get(7, 0)),
+            Some(MofosResponse::GetXattr(7, Status::Err(libc::ENODATA), _))
+        ));
+    }
+
+    #[test]
+    fn server_statfs_test() {
+        let (mut srv, _tmp) = setup_test();
+
+        match srv.handle_request(CLIENT, &MofosRequest::StatFs { id: 1 }) {
+            Some(MofosResponse::StatFs(1, Status::Ok, stats)) => assert!(stats.bavail() > 0),
+            _ => panic!("statfs failed"),
+        }
+    }
+
+    #[test]
+    fn server_collects_sessions_test() {
+        let (mut srv, tmp) = setup_test();
+
+        fs::write(tmp.to_path_buf().join("file"), b"content").expect("failed to create file");
+
+        let open = MofosRequest::Open {
+            id: 1,
+            path: String::from("file"),
+            flags: 0,
+        };
+        let fh = match srv.handle_request(CLIENT, &open) {
+            Some(MofosResponse::Open(1, Status::Ok, fh)) => fh,
+            _ => panic!("open failed"),
+        };
+
+        srv.collect_sessions(Instant::now());
+        assert!(srv.file(CLIENT, fh).is_ok());
+
+        // the client did not send anything for too long
+        srv.collect_sessions(Instant::now() + SESSION_TIMEOUT);
+        assert!(srv.file(CLIENT, fh).is_err());
+    }
+
+    #[test]
+    fn server_keepalive_test() {
+        let (mut srv, _tmp) = setup_test();
+        let started = srv.sessions[&CLIENT].last_seen;
+
+        thread::sleep(Duration::from_millis(1));
+
+        assert!(matches!(
+            srv.handle_request(CLIENT, &MofosRequest::KeepAlive { id: 1 }),
+            Some(MofosResponse::KeepAlive(1, Status::Ok))
+        ));
+
+        // the session is timed out from the keepalive on
+        srv.collect_sessions(started + SESSION_TIMEOUT);
+        assert!(srv.sessions.contains_key(&CLIENT));
+    }
+
+    #[test]
+    fn server_exit_closes_handles_test() {
+        let (mut srv, tmp) = setup_test();
+
+        fs::write(tmp.to_path_buf().join("file"), b"content").expect("failed to create file");
+
+        let open = MofosRequest::Open {
+            id: 1,
+            path: String::from("file"),
+            flags: 0,
+        };
+        let fh = match srv.handle_request(CLIENT, &open) {
+            Some(MofosResponse::Open(1, Status::Ok, fh)) => fh,
+            _ => panic!("open failed"),
+        };
+
+        assert!(srv
+            .handle_request(CLIENT, &MofosRequest::Exit { id: 2 })
+            .is_none());
+        assert!(srv.file(CLIENT, fh).is_err());
+    }
+
+    #[test]
+    fn server_hello_test() {
+        let (mut srv, _tmp) = setup_test();
+        let hello = |id, version| MofosRequest::Hello {
+            id,
+            hello: Hello {
+                version,
+                ..Default::default()
+            },
+        };
+
+        match srv.handle_request(CLIENT, &hello(1, PROTOCOL_VERSION)) {
+            Some(MofosResponse::Hello(1, Status::Ok, hello)) => {
+                assert_eq!(hello.version, PROTOCOL_VERSION)
+            }
+            _ => panic!("hello failed"),
+        }
+
+        // the server still says what it speaks when refusing
+        match srv.handle_request(CLIENT, &hello(2, PROTOCOL_VERSION + 1)) {
+            Some(MofosResponse::Hello(2, Status::Err(libc::EPROTONOSUPPORT), hello)) => {
+                assert_eq!(hello.version, PROTOCOL_VERSION)
+            }
+            _ => panic!("mismatched hello accepted"),
+        }
+
+        // nothing else is done for a client whose hello was refused
+        assert!(matches!(
+            srv.handle_request(CLIENT, &MofosRequest::StatFs { id: 3 }),
+            Some(MofosResponse::StatFs(3, Status::Err(libc::EPROTO), _))
+        ));
+    }
+
+    #[test]
+    fn server_requires_hello_test() {
+        let (mut srv, tmp) = setup_test();
+
+        fs::write(tmp.to_path_buf().join("file"), b"content").expect("failed to create file");
+
+        let unlink = MofosRequest::Unlink {
+            id: 1,
+            parent: String::new(),
+            name: String::from("file"),
+        };
 
-        // TODO: create file and access it through server
+        assert!(matches!(
+            srv.handle_request(OTHER_CLIENT, &unlink),
+            Some(MofosResponse::Unlink(1, Status::Err(libc::EPROTO)))
+        ));
+        assert!(tmp.to_path_buf().join("file").exists());
     }
 }
@@ -0,0 +1,122 @@
+//! Extended attributes of the exported files, symbolic links are never
+//! followed so the attributes of a link are its own just like its other
+//! attributes.
+
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::io::Error;
+use std::path::Path;
+
+use super::Xattr;
+#[cfg(target_os = "linux")]
+use super::{c_path, libc};
+
+/// Largest value or list of names the kernel handles, larger requested
+/// sizes are capped to it
+#[cfg(target_os = "linux")]
+const XATTR_SIZE_MAX: usize = 64 << 10;
+
+/// Runs `call` with a buffer of `size` bytes, a `size` of 0 only probes for
+/// the size that is needed
+#[cfg(target_os = "linux")]
+fn sized<F>(size: u32, call: F) -> Result<Xattr, Error>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    if size == 0 {
+        return match call(std::ptr::null_mut(), 0) {
+            len if len < 0 => Err(Error::last_os_error()),
+            len => Ok(Xattr::Size(len as u32)),
+        };
+    }
+
+    let mut buf = vec![0; (size as usize).min(XATTR_SIZE_MAX)];
+
+    match call(buf.as_mut_ptr() as *mut libc::c_void, buf.len()) {
+        len if len < 0 => Err(Error::last_os_error()),
+        len => {
+            buf.truncate(len as usize);
+            Ok(Xattr::Data(buf))
+        }
+    }
+}
+
+/// Value of the attribute `name` of `path`
+#[cfg(target_os = "linux")]
+pub fn get(path: &Path, name: &str, size: u32) -> Result<Xattr, Error> {
+    let (path, name) = (c_path(path)?, CString::new(name)?);
+
+    sized(size, |buf, len| unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, len)
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub fn set(path: &Path, name: &str, value: &[u8], flags: u32) -> Result<(), Error> {
+    let (path, name) = (c_path(path)?, CString::new(name)?);
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+
+    if unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value_ptr,
+            value.len(),
+            flags as libc::c_int,
+        )
+    } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Names of the attributes of `path`, each terminated by a NUL byte
+#[cfg(target_os = "linux")]
+pub fn list(path: &Path, size: u32) -> Result<Xattr, Error> {
+    let path = c_path(path)?;
+
+    sized(size, |buf, len| unsafe {
+        libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, len)
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub fn remove(path: &Path, name: &str) -> Result<(), Error> {
+    let (path, name) = (c_path(path)?, CString::new(name)?);
+
+    if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> Error {
+    Error::new(
+        std::io::ErrorKind::Unsupported,
+        "extended attributes are not supported",
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_path: &Path, _name: &str, _size: u32) -> Result<Xattr, Error> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set(_path: &Path, _name: &str, _value: &[u8], _flags: u32) -> Result<(), Error> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list(_path: &Path, _size: u32) -> Result<Xattr, Error> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn remove(_path: &Path, _name: &str) -> Result<(), Error> {
+    Err(unsupported())
+}
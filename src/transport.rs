@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io::Error;
+#[cfg(not(feature = "client"))]
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+mod frag;
+mod stdio;
+mod tcp;
+mod udp;
+
+pub use self::frag::{DEFAULT_MTU, MAX_MTU, MIN_MTU};
+#[cfg(feature = "client")]
+pub use self::stdio::{remote_command, PipeTransport};
+#[cfg(feature = "client")]
+pub use self::tcp::TcpTransport;
+#[cfg(feature = "client")]
+pub use self::udp::{RetryPolicy, UdpTransport};
+
+#[cfg(not(feature = "client"))]
+pub use self::stdio::StdioListener;
+#[cfg(not(feature = "client"))]
+pub use self::tcp::TcpListener;
+#[cfg(not(feature = "client"))]
+pub use self::udp::UdpListener;
+
+/// Largest serialized request or response accepted by any transport
+pub const MAX_MESSAGE: usize = 16 << 20;
+
+/// Identifies the client a request was received from
+#[cfg(not(feature = "client"))]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Peer {
+    Net(SocketAddr),
+    /// The only client, connected through the standard streams
+    Stdio,
+}
+
+#[cfg(not(feature = "client"))]
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Net(addr) => write!(f, "{}", addr),
+            Peer::Stdio => write!(f, "stdio"),
+        }
+    }
+}
+
+/// Client side of a transport
+#[cfg(any(feature = "client", test))]
+pub trait Transport: Send {
+    /// Sends the serialized request `id` to the server and waits for the
+    /// serialized response
+    fn call(&mut self, id: u64, req: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Sends the serialized request `id`, which gets no response, to the
+    /// server. Delivery is not guaranteed.
+    fn send(&mut self, id: u64, req: &[u8]) -> Result<(), Error>;
+}
+
+/// Server side of a transport
+#[cfg(not(feature = "client"))]
+pub trait Listener {
+    /// Waits for the next complete serialized request from any client
+    fn recv(&mut self) -> Result<(Peer, Vec<u8>), Error>;
+
+    /// Sends the serialized response `id` to `peer`
+    fn send(&mut self, peer: Peer, id: u64, resp: &[u8]) -> Result<(), Error>;
+
+    /// Whether clients hold a connection, whose end is then reported by
+    /// `disconnected`. Otherwise a client is only known to be gone once it
+    /// stops sending requests.
+    fn is_connected(&self) -> bool {
+        false
+    }
+
+    /// Clients whose connection ended since the last call
+    fn disconnected(&mut self) -> Vec<Peer> {
+        Vec::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TransportKind {
+    #[default]
+    Udp,
+    Tcp,
+    /// Standard streams of the server, tunneled through ssh by the client
+    Stdio,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(TransportKind::Udp),
+            "tcp" => Ok(TransportKind::Tcp),
+            "stdio" | "ssh" => Ok(TransportKind::Stdio),
+            _ => Err(format!("unknown transport {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportKind::Udp => write!(f, "udp"),
+            TransportKind::Tcp => write!(f, "tcp"),
+            TransportKind::Stdio => write!(f, "stdio"),
+        }
+    }
+}
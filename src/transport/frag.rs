@@ -0,0 +1,360 @@
+extern crate bincode;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use self::bincode::{deserialize, serialize};
+
+use super::MAX_MESSAGE;
+
+/// Default size of the datagrams we send
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Smallest MTU we accept, we need room for the header and some payload
+pub const MIN_MTU: usize = 576;
+
+/// Largest MTU we accept, the largest payload of a UDP datagram over IPv4
+pub const MAX_MTU: usize = 65507;
+
+/// Largest datagram that may be received, regardless of the configured MTU
+pub const MAX_DATAGRAM: usize = 65536;
+
+/// Size of a serialized `Fragment` without its data
+const FRAGMENT_OVERHEAD: usize = 20;
+
+/// Number of incomplete messages kept around at any given time
+const MAX_PENDING: usize = 64;
+
+/// Bytes held by all the incomplete messages at any given time, anyone able
+/// to send us datagrams can start messages
+const MAX_PENDING_SIZE: usize = 64 << 20;
+
+/// Time after which an incomplete message is discarded
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One datagram worth of a larger message
+#[derive(Serialize, Deserialize)]
+struct Fragment {
+    /// Identifier of the message this fragment is part of
+    msg: u64,
+    index: u16,
+    count: u16,
+    data: Vec<u8>,
+}
+
+/// Splits `payload` into datagrams no larger than `size`, which is below the
+/// MTU when the transport adds its own header to every datagram
+pub fn fragment(msg: u64, payload: &[u8], size: usize) -> Result<Vec<Vec<u8>>, Error> {
+    if size <= FRAGMENT_OVERHEAD {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "fragment size too small",
+        ));
+    }
+
+    if payload.len() > MAX_MESSAGE {
+        return Err(Error::new(ErrorKind::InvalidInput, "message too large"));
+    }
+
+    let chunk = size - FRAGMENT_OVERHEAD;
+    let count = payload.len().div_ceil(chunk).max(1);
+
+    if count > u16::MAX as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "too many fragments"));
+    }
+
+    let mut datagrams = Vec::with_capacity(count);
+
+    for index in 0..count {
+        let start = index * chunk;
+        let end = payload.len().min(start + chunk);
+        let frag = Fragment {
+            msg,
+            index: index as u16,
+            count: count as u16,
+            data: Vec::from(&payload[start..end]),
+        };
+
+        datagrams.push(serialize(&frag).map_err(|e| Error::new(ErrorKind::InvalidData, e))?);
+    }
+
+    Ok(datagrams)
+}
+
+/// A message for which some fragments are still missing
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects fragments until all the parts of a message have been received
+pub struct Reassembler {
+    pending: HashMap<(SocketAddr, u64), Partial>,
+    timeout: Duration,
+    /// Bytes held by the incomplete messages
+    size: usize,
+    capacity: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::with_timeout(REASSEMBLY_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Reassembler {
+        Reassembler {
+            pending: HashMap::new(),
+            timeout,
+            size: 0,
+            capacity: MAX_PENDING_SIZE,
+        }
+    }
+
+    /// Handles a datagram received from `from`, returns the identifier and
+    /// payload of the message it completes if any
+    pub fn push(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<(u64, Vec<u8>)>, Error> {
+        let frag: Fragment =
+            deserialize(datagram).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        if frag.count == 0 || frag.index >= frag.count {
+            return Err(Error::new(ErrorKind::InvalidData, "bad fragment index"));
+        }
+
+        if frag.count == 1 {
+            return Ok(Some((frag.msg, frag.data)));
+        }
+
+        self.expire();
+
+        let key = (from, frag.msg);
+
+        if !self.pending.contains_key(&key) {
+            if self.pending.len() >= MAX_PENDING {
+                self.evict_oldest(key);
+            }
+
+            self.pending.insert(
+                key,
+                Partial {
+                    fragments: vec![None; frag.count as usize],
+                    received: 0,
+                    size: 0,
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let partial = self.pending.get_mut(&key).expect("missing partial message");
+
+        if partial.fragments.len() != frag.count as usize {
+            self.remove(key);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "inconsistent fragment count",
+            ));
+        }
+
+        // duplicated fragments are expected when the sender retransmits
+        if partial.fragments[frag.index as usize].is_some() {
+            return Ok(None);
+        }
+
+        if partial.size + frag.data.len() > MAX_MESSAGE {
+            self.remove(key);
+            return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+        }
+
+        // the oldest messages make room for the newest ones
+        while self.size + frag.data.len() > self.capacity {
+            if !self.evict_oldest(key) {
+                self.remove(key);
+                return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+            }
+        }
+
+        let partial = self.pending.get_mut(&key).expect("missing partial message");
+
+        self.size += frag.data.len();
+        partial.size += frag.data.len();
+        partial.received += 1;
+        partial.fragments[frag.index as usize] = Some(frag.data);
+
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+
+        let partial = self.remove(key).expect("missing partial message");
+        let mut payload = Vec::with_capacity(partial.size);
+
+        for data in partial.fragments.into_iter().flatten() {
+            payload.extend(data);
+        }
+
+        Ok(Some((frag.msg, payload)))
+    }
+
+    fn remove(&mut self, key: (SocketAddr, u64)) -> Option<Partial> {
+        let partial = self.pending.remove(&key)?;
+
+        self.size -= partial.size;
+
+        Some(partial)
+    }
+
+    /// Drops incomplete messages that did not make progress in time
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        let size = &mut self.size;
+
+        self.pending.retain(|&(from, msg), partial| {
+            let alive = partial.started.elapsed() < timeout;
+
+            if !alive {
+                debug!("discarding incomplete message {} from {}", msg, from);
+                *size -= partial.size;
+            }
+
+            alive
+        });
+    }
+
+    /// Drops the oldest incomplete message other than `keep`, returns whether
+    /// there was one
+    fn evict_oldest(&mut self, keep: (SocketAddr, u64)) -> bool {
+        let oldest = self
+            .pending
+            .iter()
+            .filter(|(&key, _)| key != keep)
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(key, _)| *key);
+
+        match oldest {
+            Some(key) => self.remove(key).is_some(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::MAX_MESSAGE;
+    use super::{fragment, Reassembler, DEFAULT_MTU};
+
+    const PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000));
+
+    fn payload(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn small_message_single_fragment_test() {
+        let data = payload(100);
+        let frags = fragment(1, &data, DEFAULT_MTU).expect("fragmentation failed");
+        let mut reasm = Reassembler::new();
+
+        assert_eq!(frags.len(), 1);
+        assert_eq!(
+            reasm.push(PEER, &frags[0]).expect("bad fragment"),
+            Some((1, data))
+        );
+    }
+
+    #[test]
+    fn fragments_fit_mtu_test() {
+        let frags = fragment(1, &payload(10000), DEFAULT_MTU).expect("fragmentation failed");
+
+        assert!(frags.len() > 1);
+        assert!(frags.iter().all(|f| f.len() <= DEFAULT_MTU));
+    }
+
+    #[test]
+    fn reassemble_out_of_order_test() {
+        let data = payload(10000);
+        let mut frags = fragment(7, &data, DEFAULT_MTU).expect("fragmentation failed");
+        let mut reasm = Reassembler::new();
+        let last = frags.pop().unwrap();
+
+        frags.reverse();
+
+        for frag in frags.iter() {
+            assert_eq!(reasm.push(PEER, frag).expect("bad fragment"), None);
+            // duplicates must not complete the message early
+            assert_eq!(reasm.push(PEER, frag).expect("bad fragment"), None);
+        }
+
+        assert_eq!(
+            reasm.push(PEER, &last).expect("bad fragment"),
+            Some((7, data))
+        );
+    }
+
+    #[test]
+    fn incomplete_message_expires_test() {
+        let data = payload(5000);
+        let frags = fragment(3, &data, DEFAULT_MTU).expect("fragmentation failed");
+        let mut reasm = Reassembler::with_timeout(Duration::from_millis(10));
+
+        for frag in frags.iter().skip(1) {
+            reasm.push(PEER, frag).expect("bad fragment");
+        }
+
+        thread::sleep(Duration::from_millis(20));
+
+        // the first fragment now starts a new message instead of completing
+        // the expired one
+        assert_eq!(reasm.push(PEER, &frags[0]).expect("bad fragment"), None);
+    }
+
+    #[test]
+    fn pending_size_bounded_test() {
+        let mut reasm = Reassembler::new();
+
+        reasm.capacity = 4 * DEFAULT_MTU;
+
+        // every message is missing its last fragment
+        for msg in 0..8 {
+            let frags = fragment(msg, &payload(3 * DEFAULT_MTU), DEFAULT_MTU)
+                .expect("fragmentation failed");
+
+            for frag in frags.iter().skip(1) {
+                assert_eq!(reasm.push(PEER, frag).expect("bad fragment"), None);
+            }
+
+            assert!(reasm.size <= reasm.capacity);
+        }
+
+        // the newest message is still there
+        let data = payload(3 * DEFAULT_MTU);
+        let frags = fragment(7, &data, DEFAULT_MTU).expect("fragmentation failed");
+
+        assert_eq!(
+            reasm.push(PEER, &frags[0]).expect("bad fragment"),
+            Some((7, data))
+        );
+        assert_eq!(reasm.size, 0);
+    }
+
+    #[test]
+    fn oversized_message_rejected_test() {
+        assert!(fragment(1, &payload(MAX_MESSAGE + 1), DEFAULT_MTU).is_err());
+    }
+
+    #[test]
+    fn garbage_rejected_test() {
+        let mut reasm = Reassembler::new();
+
+        assert!(reasm.push(PEER, &[1, 2, 3]).is_err());
+    }
+}
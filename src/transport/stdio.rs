@@ -0,0 +1,187 @@
+#[cfg(not(feature = "client"))]
+use std::io::{self, Stdin, Stdout};
+use std::io::{Error, ErrorKind};
+#[cfg(any(feature = "client", test))]
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use super::tcp::{read_frame, write_frame};
+#[cfg(any(feature = "client", test))]
+use super::Transport;
+#[cfg(not(feature = "client"))]
+use super::{Listener, Peer};
+
+/// Client transport talking to a server started as a child process through
+/// its standard streams. The child is usually `ssh` which gives us
+/// encryption and authentication without opening any port on the remote host.
+#[cfg(any(feature = "client", test))]
+pub struct PipeTransport {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+#[cfg(any(feature = "client", test))]
+impl PipeTransport {
+    /// Runs `launcher` with the shell command line `remote` as its last
+    /// argument, e.g. `ssh host` to run the server on a remote host or
+    /// `sh -c` to run it locally
+    pub fn spawn(launcher: &[String], remote: &[String]) -> Result<PipeTransport, Error> {
+        let mut child = remote_command(launcher, remote)?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("missing child stdin");
+        let stdout = child.stdout.take().expect("missing child stdout");
+
+        Ok(PipeTransport {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+#[cfg(any(feature = "client", test))]
+impl Transport for PipeTransport {
+    fn call(&mut self, _id: u64, req: &[u8]) -> Result<Vec<u8>, Error> {
+        write_frame(&mut self.stdin, req)?;
+        read_frame(&mut self.stdout)
+    }
+
+    fn send(&mut self, _id: u64, req: &[u8]) -> Result<(), Error> {
+        write_frame(&mut self.stdin, req)
+    }
+}
+
+#[cfg(any(feature = "client", test))]
+impl Drop for PipeTransport {
+    fn drop(&mut self) {
+        // ssh may outlive the server so don't rely on closing stdin
+        if let Err(e) = self.child.kill() {
+            debug!("server already exited: {}", e);
+        }
+
+        if let Err(e) = self.child.wait() {
+            warn!("failed to reap server process: {}", e);
+        }
+    }
+}
+
+/// Builds the command running `launcher` with the shell command line made of
+/// `remote` as its last argument. The first element of `remote` is the program
+/// and is left unquoted so that it may use shell expansions such as `~`.
+#[cfg(any(feature = "client", test))]
+pub fn remote_command(launcher: &[String], remote: &[String]) -> Result<Command, Error> {
+    let (program, args) = match launcher.split_first() {
+        Some(split) => split,
+        None => return Err(Error::new(ErrorKind::InvalidInput, "empty launcher")),
+    };
+    let cmdline = match remote.split_first() {
+        Some((binary, args)) => args.iter().fold(binary.clone(), |mut line, arg| {
+            line.push(' ');
+            line.push_str(&shell_quote(arg));
+            line
+        }),
+        None => return Err(Error::new(ErrorKind::InvalidInput, "empty remote command")),
+    };
+
+    debug!("spawning {} {:?} {}", program, args, cmdline);
+
+    let mut command = Command::new(program);
+
+    command.args(args).arg(cmdline);
+
+    Ok(command)
+}
+
+/// Quotes `arg` so that it is passed verbatim through a shell command line
+#[cfg(any(feature = "client", test))]
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,".contains(c))
+    {
+        return String::from(arg);
+    }
+
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Server side of the standard streams transport, there is only ever one
+/// client and it goes away when stdin is closed
+#[cfg(not(feature = "client"))]
+pub struct StdioListener {
+    stdin: Stdin,
+    stdout: Stdout,
+}
+
+#[cfg(not(feature = "client"))]
+impl StdioListener {
+    pub fn new() -> StdioListener {
+        StdioListener {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+        }
+    }
+}
+
+#[cfg(not(feature = "client"))]
+impl Listener for StdioListener {
+    fn recv(&mut self) -> Result<(Peer, Vec<u8>), Error> {
+        let frame = read_frame(&mut self.stdin.lock())?;
+
+        Ok((Peer::Stdio, frame))
+    }
+
+    fn send(&mut self, peer: Peer, _id: u64, resp: &[u8]) -> Result<(), Error> {
+        if peer != Peer::Stdio {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a stdio peer"));
+        }
+
+        write_frame(&mut self.stdout.lock(), resp)
+    }
+
+    // the server stops along with the only client
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Transport;
+    use super::{shell_quote, PipeTransport};
+
+    fn sh() -> Vec<String> {
+        vec![String::from("sh"), String::from("-c")]
+    }
+
+    #[test]
+    fn shell_quote_test() {
+        assert_eq!(shell_quote("/tmp/dir"), "/tmp/dir");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn pipe_roundtrip_test() {
+        // cat echoes every frame back which is all a transport test needs
+        let mut transport =
+            PipeTransport::spawn(&sh(), &[String::from("cat")]).expect("spawn failed");
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        assert_eq!(transport.call(0, &data).expect("call failed"), data);
+        assert_eq!(transport.call(1, b"hello").expect("call failed"), b"hello");
+    }
+
+    #[test]
+    fn pipe_server_exit_test() {
+        let mut transport =
+            PipeTransport::spawn(&sh(), &[String::from("true")]).expect("spawn failed");
+
+        assert!(transport.call(0, b"hello").is_err());
+    }
+}
@@ -0,0 +1,505 @@
+#[cfg(not(feature = "client"))]
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+#[cfg(not(feature = "client"))]
+use std::net;
+use std::net::{SocketAddr, TcpStream};
+#[cfg(not(feature = "client"))]
+use std::sync::mpsc::{self, Receiver, Sender};
+#[cfg(not(feature = "client"))]
+use std::sync::{Arc, Mutex};
+use std::thread;
+#[cfg(any(feature = "client", test))]
+use std::time::{Duration, Instant};
+
+#[cfg(any(feature = "client", test))]
+use super::super::secure::ClientChannel;
+use super::super::secure::{Key, OVERHEAD};
+#[cfg(not(feature = "client"))]
+use super::super::secure::{Received, ServerChannel};
+#[cfg(any(feature = "client", test))]
+use super::Transport;
+use super::MAX_MESSAGE;
+#[cfg(not(feature = "client"))]
+use super::{Listener, Peer};
+
+/// Size of the length prefix in front of every frame
+const HEADER_SIZE: usize = 4;
+
+/// Upper bound on the time between two connection attempts
+#[cfg(any(feature = "client", test))]
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads one length prefixed frame from `stream`
+pub fn read_frame<R: Read>(stream: &mut R) -> Result<Vec<u8>, Error> {
+    let mut header = [0u8; HEADER_SIZE];
+
+    stream.read_exact(&mut header)?;
+
+    let len = u32::from_be_bytes(header) as usize;
+
+    if len > MAX_MESSAGE + OVERHEAD {
+        return Err(Error::new(ErrorKind::InvalidData, "frame too large"));
+    }
+
+    let mut frame = vec![0u8; len];
+
+    stream.read_exact(&mut frame)?;
+
+    Ok(frame)
+}
+
+/// Writes `data` to `stream` as a single length prefixed frame
+pub fn write_frame<W: Write>(stream: &mut W, data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_MESSAGE + OVERHEAD {
+        return Err(Error::new(ErrorKind::InvalidInput, "frame too large"));
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Stream transport, the connection takes care of retransmissions and
+/// ordering so requests are simply written and answered in order
+#[cfg(any(feature = "client", test))]
+pub struct TcpTransport {
+    stream: TcpStream,
+    /// Authenticates and encrypts every frame when a key is configured
+    secure: Option<ClientChannel>,
+}
+
+#[cfg(any(feature = "client", test))]
+impl TcpTransport {
+    /// Connects to the server at `dest`, retrying refused connections for up
+    /// to `wait` since a server we just started may not be listening yet
+    pub fn connect(
+        dest: SocketAddr,
+        key: Option<&Key>,
+        wait: Duration,
+    ) -> Result<TcpTransport, Error> {
+        let deadline = Instant::now() + wait;
+        let mut delay = Duration::from_millis(50);
+
+        let stream = loop {
+            match TcpStream::connect(dest) {
+                Ok(stream) => break stream,
+
+                Err(ref e)
+                    if e.kind() == ErrorKind::ConnectionRefused && Instant::now() < deadline =>
+                {
+                    debug!("{} refused connection, retrying in {:?}", dest, delay);
+                    thread::sleep(delay.min(deadline - Instant::now()));
+                    delay = (delay * 2).min(MAX_CONNECT_DELAY);
+                }
+
+                Err(e) => return Err(e),
+            }
+        };
+
+        stream.set_nodelay(true)?;
+
+        Ok(TcpTransport {
+            stream,
+            secure: key.map(ClientChannel::new).transpose()?,
+        })
+    }
+
+    /// Sends `req` sealed in a session opened first if needed
+    fn call_secure(
+        stream: &mut TcpStream,
+        secure: &mut ClientChannel,
+        req: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        if !secure.is_open() {
+            write_frame(stream, &secure.hello()?)?;
+            secure.accept(&read_frame(stream)?)?;
+        }
+
+        write_frame(stream, &secure.seal(req)?)?;
+        secure.open(&read_frame(stream)?)
+    }
+}
+
+#[cfg(any(feature = "client", test))]
+impl Transport for TcpTransport {
+    fn call(&mut self, _id: u64, req: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.secure {
+            Some(ref mut secure) => {
+                match TcpTransport::call_secure(&mut self.stream, secure, req) {
+                    // the server forgot our session, the request was not handled
+                    Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
+                        debug!("server lost our session, opening a new one");
+                        TcpTransport::call_secure(&mut self.stream, secure, req)
+                    }
+
+                    result => result,
+                }
+            }
+
+            None => {
+                write_frame(&mut self.stream, req)?;
+                read_frame(&mut self.stream)
+            }
+        }
+    }
+
+    fn send(&mut self, _id: u64, req: &[u8]) -> Result<(), Error> {
+        match self.secure {
+            // without a session the server would not know who it is from
+            Some(ref secure) if !secure.is_open() => Ok(()),
+            Some(ref mut secure) => write_frame(&mut self.stream, &secure.seal(req)?),
+            None => write_frame(&mut self.stream, req),
+        }
+    }
+}
+
+#[cfg(not(feature = "client"))]
+type Streams = Arc<Mutex<HashMap<SocketAddr, TcpStream>>>;
+
+#[cfg(not(feature = "client"))]
+type Secure = Arc<Mutex<Option<ServerChannel>>>;
+
+/// Clients whose connection ended and that were not reported yet
+#[cfg(not(feature = "client"))]
+type Gone = Arc<Mutex<Vec<SocketAddr>>>;
+
+/// Server side of the stream transport, every connection is read by its own
+/// thread and the complete frames are handed to `recv`
+#[cfg(not(feature = "client"))]
+pub struct TcpListener {
+    #[cfg(test)]
+    local: SocketAddr,
+    incoming: Receiver<(SocketAddr, Vec<u8>)>,
+    streams: Streams,
+    /// Rejects frames not sealed with the key when one is configured
+    secure: Secure,
+    gone: Gone,
+}
+
+#[cfg(not(feature = "client"))]
+impl TcpListener {
+    pub fn bind(addr: SocketAddr, key: Option<Key>) -> Result<TcpListener, Error> {
+        let listener = net::TcpListener::bind(addr)?;
+        #[cfg(test)]
+        let local = listener.local_addr()?;
+        let (sender, incoming) = mpsc::channel();
+        let streams: Streams = Arc::new(Mutex::new(HashMap::new()));
+        let secure: Secure = Arc::new(Mutex::new(key.map(ServerChannel::new)));
+        let gone: Gone = Arc::new(Mutex::new(Vec::new()));
+        let accepted = streams.clone();
+        let opener = secure.clone();
+        let closed = gone.clone();
+
+        thread::spawn(move || accept_loop(listener, accepted, opener, closed, sender));
+
+        Ok(TcpListener {
+            #[cfg(test)]
+            local,
+            incoming,
+            streams,
+            secure,
+            gone,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.local)
+    }
+}
+
+#[cfg(not(feature = "client"))]
+fn accept_loop(
+    listener: net::TcpListener,
+    streams: Streams,
+    secure: Secure,
+    gone: Gone,
+    sender: Sender<(SocketAddr, Vec<u8>)>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let (peer, writer) = match stream
+            .peer_addr()
+            .and_then(|p| Ok((p, stream.try_clone()?)))
+        {
+            Ok(connection) => connection,
+
+            Err(e) => {
+                warn!("failed to setup connection: {}", e);
+                continue;
+            }
+        };
+
+        debug!("accepted connection from {}", peer);
+
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("failed to disable nagle for {}: {}", peer, e);
+        }
+
+        streams.lock().unwrap().insert(peer, writer);
+
+        let streams = streams.clone();
+        let secure = secure.clone();
+        let gone = gone.clone();
+        let sender = sender.clone();
+
+        thread::spawn(move || read_loop(peer, stream, streams, secure, gone, sender));
+    }
+}
+
+#[cfg(not(feature = "client"))]
+fn read_loop(
+    peer: SocketAddr,
+    mut stream: TcpStream,
+    streams: Streams,
+    secure: Secure,
+    gone: Gone,
+    sender: Sender<(SocketAddr, Vec<u8>)>,
+) {
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(frame) => match *secure.lock().unwrap() {
+                Some(ref mut secure) => secure.open(peer, &frame),
+                None => Ok(Received::Message(frame)),
+            },
+
+            Err(e) => Err(e),
+        };
+
+        match frame {
+            Ok(Received::Message(frame)) => {
+                if sender.send((peer, frame)).is_err() {
+                    break;
+                }
+            }
+
+            // responses are written under the same lock, frames never mix
+            Ok(Received::Reply(reply)) => {
+                let written = match streams.lock().unwrap().get_mut(&peer) {
+                    Some(writer) => write_frame(writer, &reply),
+                    None => break,
+                };
+
+                if let Err(e) = written {
+                    warn!("failed to answer {}: {}", peer, e);
+                    break;
+                }
+            }
+
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                debug!("{} disconnected", peer);
+                break;
+            }
+
+            Err(e) => {
+                warn!("failed to read from {}: {}", peer, e);
+                break;
+            }
+        }
+    }
+
+    streams.lock().unwrap().remove(&peer);
+    gone.lock().unwrap().push(peer);
+}
+
+#[cfg(not(feature = "client"))]
+impl Listener for TcpListener {
+    fn recv(&mut self) -> Result<(Peer, Vec<u8>), Error> {
+        match self.incoming.recv() {
+            Ok((addr, frame)) => Ok((Peer::Net(addr), frame)),
+            Err(_) => Err(Error::new(ErrorKind::BrokenPipe, "listener stopped")),
+        }
+    }
+
+    fn send(&mut self, peer: Peer, _id: u64, resp: &[u8]) -> Result<(), Error> {
+        let addr = match peer {
+            Peer::Net(addr) => addr,
+            Peer::Stdio => return Err(Error::new(ErrorKind::InvalidInput, "not a tcp peer")),
+        };
+        let sealed = match *self.secure.lock().unwrap() {
+            Some(ref mut secure) => Some(secure.seal(addr, resp)?),
+            None => None,
+        };
+        let mut streams = self.streams.lock().unwrap();
+
+        match streams.get_mut(&addr) {
+            Some(stream) => write_frame(stream, sealed.as_deref().unwrap_or(resp)),
+            None => Err(Error::new(ErrorKind::NotConnected, "client disconnected")),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn disconnected(&mut self) -> Vec<Peer> {
+        let gone = std::mem::take(&mut *self.gone.lock().unwrap());
+
+        gone.into_iter().map(Peer::Net).collect()
+    }
+}
+
+// the transports are tested against the listeners of the server build
+#[cfg(all(test, not(feature = "client")))]
+mod test {
+    use std::io::Cursor;
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::super::secure::Key;
+    use super::super::{Listener, Transport};
+    use super::{read_frame, write_frame, TcpListener, TcpTransport};
+
+    const ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+
+    #[test]
+    fn frame_roundtrip_test() {
+        let mut stream = Vec::new();
+
+        write_frame(&mut stream, b"first").expect("write failed");
+        write_frame(&mut stream, b"").expect("write failed");
+
+        let mut cursor = Cursor::new(stream);
+
+        assert_eq!(read_frame(&mut cursor).expect("read failed"), b"first");
+        assert_eq!(read_frame(&mut cursor).expect("read failed"), b"");
+        assert!(read_frame(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn oversized_frame_rejected_test() {
+        let mut cursor = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+
+        assert!(read_frame(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn tcp_roundtrip_test() {
+        let mut listener = TcpListener::bind(ADDR, None).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let server = thread::spawn(move || {
+            for id in 0..2 {
+                let (peer, req) = listener.recv().expect("recv failed");
+
+                listener.send(peer, id, &req).expect("send failed");
+            }
+        });
+
+        let mut transport =
+            TcpTransport::connect(addr, None, Duration::ZERO).expect("connect failed");
+
+        assert_eq!(transport.call(0, &data).expect("call failed"), expected);
+        assert_eq!(transport.call(1, b"hello").expect("call failed"), b"hello");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tcp_secure_roundtrip_test() {
+        let key = Key::generate().expect("no randomness");
+        let mut listener = TcpListener::bind(ADDR, Some(key.clone())).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 0, &req).expect("send failed");
+        });
+
+        let mut transport =
+            TcpTransport::connect(addr, Some(&key), Duration::ZERO).expect("connect failed");
+
+        assert_eq!(transport.call(0, b"hello").expect("call failed"), b"hello");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tcp_secure_send_test() {
+        let key = Key::generate().expect("no randomness");
+        let mut listener = TcpListener::bind(ADDR, Some(key.clone())).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 0, &req).expect("send failed");
+            listener.recv().expect("recv failed").1
+        });
+
+        let mut transport =
+            TcpTransport::connect(addr, Some(&key), Duration::ZERO).expect("connect failed");
+
+        transport.call(0, b"hello").expect("call failed");
+        transport.send(1, b"bye").expect("send failed");
+
+        assert_eq!(server.join().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn tcp_waits_for_server_test() {
+        // find a free port, nothing listens on it until the server starts
+        let addr = std::net::TcpListener::bind(ADDR)
+            .and_then(|listener| listener.local_addr())
+            .expect("bind failed");
+
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+
+            let mut listener = TcpListener::bind(addr, None).expect("bind failed");
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 0, &req).expect("send failed");
+        });
+
+        assert!(TcpTransport::connect(addr, None, Duration::ZERO).is_err());
+
+        let mut transport =
+            TcpTransport::connect(addr, None, Duration::from_secs(5)).expect("connect failed");
+
+        assert_eq!(transport.call(0, b"hello").expect("call failed"), b"hello");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tcp_reports_disconnect_test() {
+        let mut listener = TcpListener::bind(ADDR, None).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        let mut transport =
+            TcpTransport::connect(addr, None, Duration::ZERO).expect("connect failed");
+        let client = thread::spawn(move || transport.call(0, b"hello").map(|_| ()));
+        let (peer, _) = listener.recv().expect("recv failed");
+
+        listener.send(peer, 0, b"hello").expect("send failed");
+        client.join().unwrap().expect("call failed");
+
+        // the reading thread notices the connection closing on its own time
+        for _ in 0..100 {
+            if listener.disconnected().contains(&peer) {
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("disconnect of {} not reported", peer);
+    }
+}
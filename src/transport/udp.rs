@@ -0,0 +1,550 @@
+use std::io::{Error, ErrorKind};
+#[cfg(any(feature = "client", test))]
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(any(feature = "client", test))]
+use std::time::{Duration, Instant};
+
+#[cfg(any(feature = "client", test))]
+use super::super::secure::ClientChannel;
+use super::super::secure::{Key, OVERHEAD};
+#[cfg(not(feature = "client"))]
+use super::super::secure::{Received, ServerChannel};
+use super::frag::{fragment, Reassembler, MAX_DATAGRAM};
+#[cfg(any(feature = "client", test))]
+use super::Transport;
+#[cfg(not(feature = "client"))]
+use super::{Listener, Peer};
+
+/// Upper bound on the time we wait for a single transmission to be answered
+#[cfg(any(feature = "client", test))]
+const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Controls how requests are retransmitted when the server does not answer
+#[cfg(any(feature = "client", test))]
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Time to wait for a response before the first retransmission, doubled
+    /// after each unanswered attempt
+    pub timeout: Duration,
+    /// Number of retransmissions before giving up on a request
+    pub retries: u32,
+}
+
+#[cfg(any(feature = "client", test))]
+impl RetryPolicy {
+    fn timeout_for(&self, attempt: u32) -> Duration {
+        self.timeout
+            .checked_mul(1 << attempt.min(16))
+            .map_or(MAX_TIMEOUT, |t| t.min(MAX_TIMEOUT))
+    }
+}
+
+#[cfg(any(feature = "client", test))]
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_millis(500),
+            retries: 5,
+        }
+    }
+}
+
+/// Size of the fragments that fit in `mtu` once sealed
+fn fragment_size(mtu: usize, sealed: bool) -> usize {
+    if sealed {
+        mtu - OVERHEAD
+    } else {
+        mtu
+    }
+}
+
+/// Datagram transport retransmitting requests until they are answered and
+/// fragmenting messages that do not fit in the MTU
+#[cfg(any(feature = "client", test))]
+pub struct UdpTransport {
+    socket: UdpSocket,
+    dest: SocketAddr,
+    policy: RetryPolicy,
+    /// Size of the datagrams sent to the server
+    mtu: usize,
+    reassembler: Reassembler,
+    /// Authenticates and encrypts every datagram when a key is configured
+    secure: Option<ClientChannel>,
+}
+
+#[cfg(any(feature = "client", test))]
+impl UdpTransport {
+    pub fn connect(
+        dest: SocketAddr,
+        policy: RetryPolicy,
+        mtu: usize,
+        key: Option<&Key>,
+    ) -> Result<UdpTransport, Error> {
+        let local: SocketAddr = match dest {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+
+        // connecting makes the kernel drop datagrams not coming from the server
+        socket.connect(dest)?;
+
+        Ok(UdpTransport {
+            socket,
+            dest,
+            policy,
+            mtu,
+            reassembler: Reassembler::new(),
+            secure: key.map(ClientChannel::new).transpose()?,
+        })
+    }
+
+    /// Receives a datagram in `buf` before `deadline`, returns its size or
+    /// `None` if none arrived in time
+    fn recv_until(&self, deadline: Instant, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        loop {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            self.socket.set_read_timeout(Some(deadline - now))?;
+
+            match self.socket.recv(buf) {
+                Ok(recvd) => return Ok(Some(recvd)),
+
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    return Ok(None);
+                }
+
+                // the server may not be listening yet, keep retrying until the deadline
+                Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Opens a secure session with the server, retransmitting the hello
+    /// until it is answered
+    fn handshake(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let secure = match self.secure {
+            Some(ref mut secure) => secure,
+            None => return Ok(()),
+        };
+        let hello = secure.hello()?;
+
+        for attempt in 0..=self.policy.retries {
+            self.socket.send(&hello)?;
+
+            let deadline = Instant::now() + self.policy.timeout_for(attempt);
+
+            while let Some(recvd) = self.recv_until(deadline, buf)? {
+                let secure = self.secure.as_mut().expect("secure channel vanished");
+
+                match secure.accept(&buf[0..recvd]) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => debug!("ignoring datagram during handshake: {}", e),
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            "server did not answer the handshake",
+        ))
+    }
+
+    /// Sends `datagrams`, each sealed when a key is configured
+    fn transmit(&mut self, datagrams: &[Vec<u8>]) -> Result<(), Error> {
+        for datagram in datagrams {
+            match self.secure {
+                Some(ref mut secure) => self.socket.send(&secure.seal(datagram)?)?,
+                None => self.socket.send(datagram)?,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Waits until `deadline` for the response to request `id`, returns `None`
+    /// if it did not arrive in time
+    fn wait_for(
+        &mut self,
+        id: u64,
+        deadline: Instant,
+        buf: &mut [u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        while let Some(recvd) = self.recv_until(deadline, buf)? {
+            let datagram = match self.secure {
+                Some(ref mut secure) => match secure.open(&buf[0..recvd]) {
+                    Ok(datagram) => datagram,
+
+                    // the request is sent again in a new session
+                    Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
+                        debug!("server lost our session");
+                        return Ok(None);
+                    }
+
+                    Err(e) => {
+                        warn!("rejected datagram: {}", e);
+                        continue;
+                    }
+                },
+
+                None => Vec::from(&buf[0..recvd]),
+            };
+
+            match self.reassembler.push(self.dest, &datagram) {
+                // responses to earlier requests may still be in flight, skip them
+                Ok(Some((msg, _))) if msg != id => {
+                    debug!("ignoring response {} while waiting for {}", msg, id);
+                }
+
+                Ok(Some((_, payload))) => return Ok(Some(payload)),
+
+                Ok(None) => continue,
+
+                Err(e) => {
+                    warn!("invalid fragment received: {}", e);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(any(feature = "client", test))]
+impl Transport for UdpTransport {
+    fn call(&mut self, id: u64, req: &[u8]) -> Result<Vec<u8>, Error> {
+        let datagrams = fragment(id, req, fragment_size(self.mtu, self.secure.is_some()))?;
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+
+        for attempt in 0..=self.policy.retries {
+            if attempt > 0 {
+                debug!("retransmitting request {} (attempt {})", id, attempt);
+            }
+
+            if self.secure.as_ref().is_some_and(|secure| !secure.is_open()) {
+                self.handshake(&mut buf)?;
+            }
+
+            // retransmissions are sealed again, the server would reject them
+            // as replays otherwise
+            self.transmit(&datagrams)?;
+
+            let deadline = Instant::now() + self.policy.timeout_for(attempt);
+
+            if let Some(resp) = self.wait_for(id, deadline, &mut buf)? {
+                return Ok(resp);
+            }
+        }
+
+        warn!(
+            "request {} unanswered after {} retransmissions",
+            id, self.policy.retries
+        );
+
+        Err(Error::new(ErrorKind::TimedOut, "server did not answer"))
+    }
+
+    fn send(&mut self, id: u64, req: &[u8]) -> Result<(), Error> {
+        // without a session the server would not know who it is from
+        if self.secure.as_ref().is_some_and(|secure| !secure.is_open()) {
+            return Ok(());
+        }
+
+        let datagrams = fragment(id, req, fragment_size(self.mtu, self.secure.is_some()))?;
+
+        self.transmit(&datagrams)
+    }
+}
+
+/// Server side of the datagram transport
+#[cfg(not(feature = "client"))]
+pub struct UdpListener {
+    socket: UdpSocket,
+    /// Size of the datagrams sent to clients
+    mtu: usize,
+    reassembler: Reassembler,
+    buf: Vec<u8>,
+    /// Rejects datagrams not sealed with the key when one is configured
+    secure: Option<ServerChannel>,
+}
+
+#[cfg(not(feature = "client"))]
+impl UdpListener {
+    pub fn bind(addr: SocketAddr, mtu: usize, key: Option<Key>) -> Result<UdpListener, Error> {
+        Ok(UdpListener {
+            socket: UdpSocket::bind(addr)?,
+            mtu,
+            reassembler: Reassembler::new(),
+            buf: vec![0u8; MAX_DATAGRAM],
+            secure: key.map(ServerChannel::new),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr()
+    }
+}
+
+#[cfg(not(feature = "client"))]
+impl Listener for UdpListener {
+    fn recv(&mut self) -> Result<(Peer, Vec<u8>), Error> {
+        loop {
+            let (recvd, addr) = self.socket.recv_from(&mut self.buf)?;
+
+            let datagram = match self.secure {
+                Some(ref mut secure) => match secure.open(addr, &self.buf[0..recvd]) {
+                    Ok(Received::Message(datagram)) => datagram,
+
+                    Ok(Received::Reply(reply)) => {
+                        self.socket.send_to(&reply, addr)?;
+                        continue;
+                    }
+
+                    Err(e) => {
+                        warn!("rejected datagram from {}: {}", addr, e);
+                        continue;
+                    }
+                },
+
+                None => Vec::from(&self.buf[0..recvd]),
+            };
+
+            match self.reassembler.push(addr, &datagram) {
+                Ok(Some((_, payload))) => return Ok((Peer::Net(addr), payload)),
+
+                // more fragments are needed to complete this request
+                Ok(None) => continue,
+
+                Err(e) => {
+                    warn!("invalid fragment received from {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, peer: Peer, id: u64, resp: &[u8]) -> Result<(), Error> {
+        let addr = match peer {
+            Peer::Net(addr) => addr,
+            Peer::Stdio => return Err(Error::new(ErrorKind::InvalidInput, "not a udp peer")),
+        };
+
+        let size = fragment_size(self.mtu, self.secure.is_some());
+
+        for datagram in fragment(id, resp, size)? {
+            let sent = match self.secure {
+                Some(ref mut secure) => {
+                    self.socket.send_to(&secure.seal(addr, &datagram)?, addr)?
+                }
+                None => self.socket.send_to(datagram.as_slice(), addr)?,
+            };
+
+            debug!("sent {} bytes to {}", sent, peer);
+        }
+
+        Ok(())
+    }
+}
+
+// the transports are tested against the listeners of the server build
+#[cfg(all(test, not(feature = "client")))]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::super::secure::Key;
+    use super::super::{Listener, Transport, DEFAULT_MTU, MIN_MTU};
+    use super::{RetryPolicy, UdpListener, UdpTransport, MAX_TIMEOUT};
+
+    const ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        timeout: Duration::from_millis(50),
+        retries: 3,
+    };
+
+    #[test]
+    fn udp_large_message_test() {
+        let mut listener = UdpListener::bind(ADDR, DEFAULT_MTU, None).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 1, &req).expect("send failed");
+        });
+
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, DEFAULT_MTU, None).expect("connect failed");
+
+        assert_eq!(transport.call(1, &data).expect("call failed"), expected);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn udp_retransmits_lost_request_test() {
+        let mut listener = UdpListener::bind(ADDR, DEFAULT_MTU, None).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+
+        let server = thread::spawn(move || {
+            // the first transmission is lost
+            listener.recv().expect("recv failed");
+
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 2, &req).expect("send failed");
+        });
+
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, DEFAULT_MTU, None).expect("connect failed");
+
+        assert_eq!(transport.call(2, b"hello").expect("call failed"), b"hello");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn retry_backoff_test() {
+        assert_eq!(POLICY.timeout_for(0), Duration::from_millis(50));
+        assert_eq!(POLICY.timeout_for(1), Duration::from_millis(100));
+        assert_eq!(POLICY.timeout_for(3), Duration::from_millis(400));
+        assert_eq!(POLICY.timeout_for(100), MAX_TIMEOUT);
+    }
+
+    #[test]
+    fn udp_skips_stale_response_test() {
+        let mut listener = UdpListener::bind(ADDR, DEFAULT_MTU, None).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            // a late answer to an earlier request arrives first
+            listener.send(peer, 3, b"stale").expect("send failed");
+            listener.send(peer, 4, &req).expect("send failed");
+        });
+
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, DEFAULT_MTU, None).expect("connect failed");
+
+        assert_eq!(transport.call(4, b"hello").expect("call failed"), b"hello");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn udp_gives_up_after_retries_test() {
+        let listener = UdpListener::bind(ADDR, DEFAULT_MTU, None).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, DEFAULT_MTU, None).expect("connect failed");
+
+        assert!(transport.call(3, b"hello").is_err());
+    }
+
+    #[test]
+    fn udp_secure_roundtrip_test() {
+        let key = Key::generate().expect("no randomness");
+        let mut listener =
+            UdpListener::bind(ADDR, DEFAULT_MTU, Some(key.clone())).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 4, &req).expect("send failed");
+        });
+
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, DEFAULT_MTU, Some(&key)).expect("connect failed");
+
+        assert_eq!(transport.call(4, &data).expect("call failed"), expected);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn udp_secure_send_test() {
+        let key = Key::generate().expect("no randomness");
+        let mut listener =
+            UdpListener::bind(ADDR, DEFAULT_MTU, Some(key.clone())).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 5, &req).expect("send failed");
+            listener.recv().expect("recv failed").1
+        });
+
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, DEFAULT_MTU, Some(&key)).expect("connect failed");
+
+        transport.call(5, b"hello").expect("call failed");
+        transport.send(6, b"bye").expect("send failed");
+
+        assert_eq!(server.join().unwrap(), b"bye");
+    }
+
+    #[test]
+    fn udp_secure_min_mtu_test() {
+        let key = Key::generate().expect("no randomness");
+        let mut listener =
+            UdpListener::bind(ADDR, MIN_MTU, Some(key.clone())).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+        let data: Vec<u8> = (0..2_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let server = thread::spawn(move || {
+            let (peer, req) = listener.recv().expect("recv failed");
+
+            listener.send(peer, 6, &req).expect("send failed");
+        });
+
+        let mut transport =
+            UdpTransport::connect(addr, POLICY, MIN_MTU, Some(&key)).expect("connect failed");
+
+        assert_eq!(transport.call(6, &data).expect("call failed"), expected);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn udp_rejects_unauthenticated_test() {
+        let key = Key::generate().expect("no randomness");
+        let mut listener = UdpListener::bind(ADDR, DEFAULT_MTU, Some(key)).expect("bind failed");
+        let addr = listener.local_addr().expect("no local address");
+
+        // the server never sees the request so the client times out
+        let client = thread::spawn(move || {
+            let other = Key::generate().expect("no randomness");
+            let mut transport = UdpTransport::connect(addr, POLICY, DEFAULT_MTU, Some(&other))
+                .expect("connect failed");
+
+            assert!(transport.call(5, b"hello").is_err());
+        });
+
+        listener
+            .socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .expect("failed to set timeout");
+        assert!(listener.recv().is_err());
+
+        client.join().unwrap();
+    }
+}